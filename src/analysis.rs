@@ -0,0 +1,90 @@
+use crate::config::{AppConfig, PZEMDevice};
+use crate::detectors::{DetectorSet, DetectorStatePayload};
+use crate::energy_periods::{PeriodCounters, PeriodEnergyPayload, PeriodSchedule};
+use crate::events::BridgeEvent;
+use crate::power_quality::{PowerQualityPayload, PowerQualityTracker};
+use crate::readings::MeterReading;
+use crate::rules::RuleEngine;
+use crate::timestamp::Timestamp;
+use chrono::{DateTime, Utc};
+use std::time::Instant;
+
+// what the analysis made of one reading, handed to the sinks on the MeterSample
+#[derive(Debug, Clone, Default)]
+pub struct AnalysisResults {
+    // (rule name, active) for every rule on the meter
+    pub rules: Vec<(String, bool)>,
+    // (detector name, state) for every state detector on the meter
+    pub detectors: Vec<(String, DetectorStatePayload)>,
+    // only when power_quality is configured
+    pub power_quality: Option<PowerQualityPayload>,
+    // only when energy_periods are configured
+    pub periods: Option<PeriodEnergyPayload>,
+}
+
+// Everything about one meter that builds up over successive readings. It lives with the
+// poller, so it sees every reading no matter which sinks are enabled or keeping up.
+pub struct MeterAnalysis {
+    schedule: Option<PeriodSchedule>,
+    periods: PeriodCounters,
+    rules: RuleEngine,
+    detectors: DetectorSet,
+    power_quality: Option<PowerQualityTracker>,
+}
+
+impl MeterAnalysis {
+    pub fn new(device: &PZEMDevice, config: &AppConfig) -> Self {
+        let schedule = match config.energy_periods.as_ref().map(PeriodSchedule::new) {
+            Some(Ok(s)) => Some(s),
+            Some(Err(e)) => {
+                error!("energy periods disabled for {}: {e}", device.key());
+                None
+            }
+            None => None,
+        };
+        MeterAnalysis {
+            schedule,
+            periods: PeriodCounters::load(config.state_dir(), &device.key()),
+            rules: RuleEngine::new(device, config),
+            detectors: DetectorSet::new(device),
+            power_quality: config
+                .power_quality
+                .as_ref()
+                .map(|pq| PowerQualityTracker::new(pq, config.state_dir(), &device.key())),
+        }
+    }
+
+    // `energy_wh` is the meter's continuous energy counter; returns the results for this
+    // reading and the events it raised
+    pub fn update(
+        &mut self,
+        reading: &MeterReading,
+        energy_wh: f64,
+        at: DateTime<Utc>,
+        last_seen: Timestamp,
+    ) -> (AnalysisResults, Vec<BridgeEvent>) {
+        let polled_at = Instant::now();
+        let mut events = self.rules.evaluate(reading, polled_at);
+        events.extend(self.detectors.update(reading.watts, energy_wh, polled_at));
+        let power_quality = self.power_quality.as_mut().map(|pq| {
+            pq.update(reading.volts, reading.frequency, polled_at);
+            pq.payload(polled_at, last_seen.clone())
+        });
+        let periods = self.schedule.as_ref().and_then(|schedule| {
+            self.periods.update(energy_wh, at, schedule);
+            self.periods.payload(schedule)
+        });
+        let results = AnalysisResults {
+            rules: self.rules.states().into_iter().map(|(rule, active)| (rule.to_string(), active)).collect(),
+            detectors: self
+                .detectors
+                .payloads(reading.watts, polled_at, last_seen)
+                .into_iter()
+                .map(|(detector, state)| (detector.to_string(), state))
+                .collect(),
+            power_quality,
+            periods,
+        };
+        (results, events)
+    }
+}
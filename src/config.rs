@@ -1,18 +1,923 @@
+use chrono::{NaiveTime, Weekday};
+use chrono_tz::Tz;
 use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::Path;
+use crate::consts::{DEFAULT_HTTP_LISTEN_ADDR, DEFAULT_STATE_DIR, ENERGY_REGISTER_WRAP_WH, GROUP_STALE_AFTER_SECS, HISTORY_RAW_RETENTION_DAYS, HISTORY_ROLLUP_RETENTION_DAYS, GRAPHITE_FLUSH_INTERVAL_SECS, GRAPHITE_PREFIX, INFLUX_BATCH_SIZE, INFLUX_FLUSH_INTERVAL_SECS, INFLUX_MAX_RETRIES, INFLUX_TIMEOUT_SECS, WEBHOOK_EVENTS, WEBHOOK_MAX_RETRIES, WEBHOOK_TIMEOUT_SECS, POWER_QUALITY_REARM_VOLTS, POWER_QUALITY_WINDOWS_SECS};
+use crate::errors::ConfigError;
+use crate::graphite::path_node;
+use crate::tariffs::TariffSchedule;
+use crate::readings::FIELDS;
+use crate::timestamp::TimestampFormat;
 
-#[derive(Deserialize, Clone, Debug, Default)]
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
 pub struct AppConfig {
+    #[serde(default)]
     pub mqtt_server_addr: String,
     pub mqtt_server_port: Option<u16>,
     pub mqtt_client_id: Option<String>,
     pub mqtt_username: Option<String>,
     pub mqtt_password: Option<String>,
-    pub devices: Option<Vec<PZEMDevice>>
+    pub state_dir: Option<String>,
+    pub http_listen_addr: Option<String>,
+    pub http_public_url: Option<String>,
+    pub json_state: Option<bool>,
+    pub timestamp_format: Option<TimestampFormat>,
+    pub devices: Option<Vec<PZEMDevice>>,
+    pub groups: Option<Vec<MeterGroup>>,
+    pub remainders: Option<Vec<RemainderMeter>>,
+    pub energy_periods: Option<EnergyPeriods>,
+    pub energy_wrap_wh: Option<f32>,
+    pub tariffs: Option<Tariffs>,
+    pub rules: Option<Vec<AlertRule>>,
+    pub power_quality: Option<PowerQuality>,
+    pub history: Option<History>,
+    pub influx: Option<Influx>,
+    pub mqtt_sink: Option<MqttSinkSettings>,
+    pub mqtt_outputs: Option<Vec<MqttOutput>>,
+    pub prometheus: Option<Prometheus>,
+    pub file_sink: Option<FileSinkSettings>,
+    pub graphite: Option<Graphite>,
+    pub webhook: Option<Webhook>,
 }
 
-#[derive(Deserialize, Clone, Debug, Default)]
+#[derive(Deserialize, Clone, Debug, Default, PartialEq, Eq, Hash)]
 pub struct PZEMDevice {
     pub addr: u8,
     pub port: String,
     pub breaker: String,
+    pub area: Option<String>,
+    // breaker trip rating; enables the load_percent and headroom sensors
+    pub rating_amps: Option<u16>,
+    // nominal circuit voltage used for headroom, e.g. 240 on a double-pole breaker measured
+    // on one leg; the measured voltage if unset
+    pub circuit_volts: Option<u16>,
+    pub state_detectors: Option<Vec<StateDetector>>,
+}
+
+impl PZEMDevice {
+    pub fn state_detectors(&self) -> &[StateDetector] {
+        self.state_detectors.as_deref().unwrap_or_default()
+    }
+}
+
+// tells whether the appliance on a circuit is running from the power it draws
+#[derive(Deserialize, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct StateDetector {
+    pub name: String,
+    // turns on above this
+    pub on_watts: u32,
+    // turns off below this; on_watts if unset
+    pub off_watts: Option<u32>,
+    // a change has to hold this long before it is reported
+    pub debounce_secs: Option<u64>,
+    // named power ranges while on, e.g. heating/spinning
+    pub bands: Option<Vec<PowerBand>>,
+}
+
+impl StateDetector {
+    pub fn off_watts(&self) -> u32 {
+        self.off_watts.unwrap_or(self.on_watts)
+    }
+
+    pub fn bands(&self) -> &[PowerBand] {
+        self.bands.as_deref().unwrap_or_default()
+    }
+}
+
+// the band applies from min_watts up to the next band's min_watts
+#[derive(Deserialize, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct PowerBand {
+    pub name: String,
+    pub min_watts: u32,
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct MeterGroup {
+    pub name: String,
+    // breaker names of the member devices
+    pub members: Vec<String>,
+    pub stale_after: Option<u64>,
+}
+
+impl MeterGroup {
+    pub fn stale_after(&self) -> u64 {
+        self.stale_after.unwrap_or(GROUP_STALE_AFTER_SECS)
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct EnergyPeriods {
+    // IANA name, e.g. America/New_York
+    pub timezone: Option<String>,
+    // local time the day rolls over, HH:MM
+    pub reset_time: Option<String>,
+    pub week_start: Option<String>,
+}
+
+impl EnergyPeriods {
+    pub fn timezone(&self) -> Result<Tz, String> {
+        self.timezone.as_deref().unwrap_or("UTC").parse::<Tz>()
+    }
+
+    pub fn reset_time(&self) -> Result<NaiveTime, String> {
+        parse_hhmm(self.reset_time.as_deref().unwrap_or("00:00")).map_err(|e| format!("reset_time {e}"))
+    }
+
+    pub fn week_start(&self) -> Result<Weekday, String> {
+        let s = self.week_start.as_deref().unwrap_or("monday");
+        s.parse::<Weekday>().map_err(|_e| format!("week_start {s} is not a weekday"))
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Tariffs {
+    pub currency: String,
+    // defaults to the energy_periods timezone
+    pub timezone: Option<String>,
+    pub fixed_daily_charge: Option<f64>,
+    // breaker or group name the fixed daily charge is billed to; required with the charge
+    pub fixed_charge_to: Option<String>,
+    // first match wins, so list a catch-all period last; every minute of the week must be covered
+    pub periods: Vec<TariffPeriod>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct TariffPeriod {
+    pub name: String,
+    // weekdays this period applies on, every day if unset
+    pub days: Option<Vec<String>>,
+    // HH:MM local time; a period with end before start runs past midnight
+    pub start: Option<String>,
+    pub end: Option<String>,
+    pub price_per_kwh: f64,
+}
+
+impl TariffPeriod {
+    pub fn days(&self) -> Result<Option<Vec<Weekday>>, String> {
+        match &self.days {
+            None => Ok(None),
+            Some(days) => days
+                .iter()
+                .map(|d| d.parse::<Weekday>().map_err(|_e| format!("tariff {}: {d} is not a weekday", self.name)))
+                .collect::<Result<Vec<Weekday>, String>>()
+                .map(Some),
+        }
+    }
+
+    pub fn start(&self) -> Result<NaiveTime, String> {
+        parse_hhmm(self.start.as_deref().unwrap_or("00:00")).map_err(|e| format!("tariff {}: {e}", self.name))
+    }
+
+    pub fn end(&self) -> Result<NaiveTime, String> {
+        parse_hhmm(self.end.as_deref().unwrap_or("00:00")).map_err(|e| format!("tariff {}: {e}", self.name))
+    }
+}
+
+impl Tariffs {
+    pub fn timezone(&self, config: &AppConfig) -> Result<Tz, String> {
+        match (&self.timezone, &config.energy_periods) {
+            (Some(tz), _) => tz.parse::<Tz>(),
+            (None, Some(periods)) => periods.timezone(),
+            (None, None) => Ok(Tz::UTC),
+        }
+    }
+
+    pub fn fixed_charge_for(&self, name: &str) -> f64 {
+        match &self.fixed_charge_to {
+            Some(to) if to == name => self.fixed_daily_charge.unwrap_or(0.0),
+            _ => 0.0,
+        }
+    }
+}
+
+fn parse_hhmm(s: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(s, "%H:%M").map_err(|e| format!("{s}: {e}"))
+}
+
+// local sqlite store of every reading
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct History {
+    // state_dir/history.sqlite if unset
+    pub path: Option<String>,
+    pub raw_retention_days: Option<u32>,
+    // how long the 1-minute averages are kept
+    pub rollup_retention_days: Option<u32>,
+    pub queue_size: Option<usize>,
+}
+
+impl History {
+    pub fn path(&self, config: &AppConfig) -> String {
+        self.path
+            .clone()
+            .unwrap_or_else(|| Path::new(config.state_dir()).join("history.sqlite").to_string_lossy().to_string())
+    }
+
+    pub fn raw_retention_days(&self) -> u32 {
+        self.raw_retention_days.unwrap_or(HISTORY_RAW_RETENTION_DAYS)
+    }
+
+    pub fn rollup_retention_days(&self) -> u32 {
+        self.rollup_retention_days.unwrap_or(HISTORY_ROLLUP_RETENTION_DAYS)
+    }
+}
+
+// per-meter state and Home Assistant discovery over MQTT; on unless disabled here
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct MqttSinkSettings {
+    pub enabled: Option<bool>,
+    pub queue_size: Option<usize>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MqttFormat {
+    // the bare number on one topic per metric, {base_topic}/{meter id}/{metric}
+    Plain,
+    // Homie 4 devices, nodes and properties with their $ attribute topics
+    Homie,
+    // Sparkplug B topics and message shapes, encoded as JSON
+    SparkplugJson,
+    // Sparkplug B proper: protobuf payloads, births, deaths and aliases. Unlike the other
+    // formats this opens a second broker session, as {mqtt_client_id}-sparkplug with the same
+    // credentials: the spec needs the NDEATH as the session's will, and the main connection's
+    // will already tells Home Assistant the bridge is offline.
+    Sparkplug,
+}
+
+// readings published for consumers other than Home Assistant; plain and sparkplug_json share
+// the bridge's broker connection, homie and sparkplug open their own
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct MqttOutput {
+    pub format: MqttFormat,
+    // topics start here; pzem016, homie or spBv1.0 by default
+    pub base_topic: Option<String>,
+    // sparkplug group id
+    pub group_id: Option<String>,
+    pub queue_size: Option<usize>,
+}
+
+impl MqttOutput {
+    pub fn base_topic(&self) -> &str {
+        match (&self.base_topic, self.format) {
+            (Some(t), _) => t,
+            (None, MqttFormat::Plain) => "pzem016",
+            (None, MqttFormat::Homie) => "homie",
+            (None, MqttFormat::SparkplugJson | MqttFormat::Sparkplug) => "spBv1.0",
+        }
+    }
+
+    pub fn group_id(&self) -> &str {
+        self.group_id.as_deref().unwrap_or("pzem016")
+    }
+}
+
+// serves the latest readings at /metrics on the http server
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Prometheus {
+    pub queue_size: Option<usize>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FileFormat {
+    Csv,
+    #[default]
+    Ndjson,
+}
+
+// one row per reading in a file per local day, e.g. pzem016-2024-01-31.csv
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct FileSinkSettings {
+    pub directory: String,
+    pub format: Option<FileFormat>,
+    pub prefix: Option<String>,
+    // gzip each day's file (or part) once the next one starts
+    pub compress: Option<bool>,
+    // oldest files are deleted once the directory holds more than this; a day's file is also
+    // split into numbered parts of a quarter of this, e.g. pzem016-2024-01-31.1.csv
+    pub max_total_mb: Option<u64>,
+    pub queue_size: Option<usize>,
+}
+
+impl FileSinkSettings {
+    pub fn format(&self) -> FileFormat {
+        self.format.unwrap_or_default()
+    }
+
+    pub fn prefix(&self) -> &str {
+        self.prefix.as_deref().unwrap_or("pzem016")
+    }
+
+    pub fn compress(&self) -> bool {
+        self.compress.unwrap_or(true)
+    }
+}
+
+// InfluxDB line protocol output; any combination of the v2 write api, udp and a file
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Influx {
+    // e.g. http://influxdb:8086
+    pub url: Option<String>,
+    pub org: Option<String>,
+    pub bucket: Option<String>,
+    pub token: Option<String>,
+    // host:port of a udp listener
+    pub udp_addr: Option<String>,
+    // line protocol is appended to this file
+    pub line_file: Option<String>,
+    pub batch_size: Option<usize>,
+    pub flush_interval_secs: Option<u64>,
+    pub max_retries: Option<u32>,
+    // per write api request
+    pub timeout_secs: Option<u64>,
+    pub queue_size: Option<usize>,
+}
+
+impl Influx {
+    pub fn timeout_secs(&self) -> u64 {
+        self.timeout_secs.unwrap_or(INFLUX_TIMEOUT_SECS)
+    }
+
+    pub fn batch_size(&self) -> usize {
+        self.batch_size.unwrap_or(INFLUX_BATCH_SIZE)
+    }
+
+    pub fn flush_interval_secs(&self) -> u64 {
+        self.flush_interval_secs.unwrap_or(INFLUX_FLUSH_INTERVAL_SECS)
+    }
+
+    pub fn max_retries(&self) -> u32 {
+        self.max_retries.unwrap_or(INFLUX_MAX_RETRIES)
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum GraphiteProtocol {
+    // plaintext `path value timestamp` lines over tcp
+    #[default]
+    Graphite,
+    // `path:value|g` gauges over udp
+    Statsd,
+}
+
+// every metric as {prefix}.{breaker}.{metric}, sent once per flush interval; breaker names must
+// stay distinct once dots and whitespace are replaced
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Graphite {
+    // host:port of carbon or the statsd daemon
+    pub addr: String,
+    pub protocol: Option<GraphiteProtocol>,
+    pub prefix: Option<String>,
+    pub flush_interval_secs: Option<u64>,
+    pub queue_size: Option<usize>,
+}
+
+impl Graphite {
+    pub fn protocol(&self) -> GraphiteProtocol {
+        self.protocol.unwrap_or_default()
+    }
+
+    pub fn prefix(&self) -> &str {
+        self.prefix.as_deref().unwrap_or(GRAPHITE_PREFIX)
+    }
+
+    pub fn flush_interval_secs(&self) -> u64 {
+        self.flush_interval_secs.unwrap_or(GRAPHITE_FLUSH_INTERVAL_SECS)
+    }
+}
+
+// POSTs meter offline/online, energy reset and rule events to an http endpoint
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Webhook {
+    pub url: String,
+    pub headers: Option<BTreeMap<String, String>>,
+    // event names to send, e.g. [meter_offline, rule_triggered]; all of WEBHOOK_EVENTS if unset
+    pub events: Option<Vec<String>>,
+    // {{ field }} is replaced by that field of the event, {{ json }} by the whole event
+    pub body_template: Option<String>,
+    pub max_retries: Option<u32>,
+    pub timeout_secs: Option<u64>,
+    // events that couldn't be delivered are appended here as json lines
+    pub dead_letter_path: Option<String>,
+    pub queue_size: Option<usize>,
+}
+
+impl Webhook {
+    pub fn sends(&self, event: &str) -> bool {
+        match &self.events {
+            Some(events) => events.iter().any(|e| e == event),
+            None => WEBHOOK_EVENTS.contains(&event),
+        }
+    }
+
+    pub fn max_retries(&self) -> u32 {
+        self.max_retries.unwrap_or(WEBHOOK_MAX_RETRIES)
+    }
+
+    pub fn timeout_secs(&self) -> u64 {
+        self.timeout_secs.unwrap_or(WEBHOOK_TIMEOUT_SECS)
+    }
+
+    pub fn dead_letter_path(&self, config: &AppConfig) -> String {
+        self.dead_letter_path.clone().unwrap_or_else(|| {
+            Path::new(config.state_dir()).join("webhook_dead_letter.ndjson").to_string_lossy().to_string()
+        })
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct PowerQuality {
+    // rolling statistics windows in seconds
+    pub windows_secs: Option<Vec<u64>>,
+    // a sag or swell is counted each time the voltage crosses these
+    pub sag_below_volts: Option<f32>,
+    pub swell_above_volts: Option<f32>,
+    // how far back inside a threshold the voltage has to come before the next crossing counts
+    pub rearm_volts: Option<f32>,
+}
+
+impl PowerQuality {
+    pub fn rearm_volts(&self) -> f32 {
+        self.rearm_volts.unwrap_or(POWER_QUALITY_REARM_VOLTS)
+    }
+
+    pub fn windows_secs(&self) -> Vec<u64> {
+        self.windows_secs.clone().unwrap_or(POWER_QUALITY_WINDOWS_SECS.to_vec())
+    }
+}
+
+// fires when `metric` goes above `above` or below `below` for at least `for_secs`, and clears
+// once it is back inside the limits by `hysteresis`
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct AlertRule {
+    pub name: String,
+    // breaker names; every meter if unset
+    pub meters: Option<Vec<String>>,
+    pub metric: String,
+    pub above: Option<f32>,
+    pub below: Option<f32>,
+    // amps rules only: percent of the meter's rating_amps, e.g. 80 for the continuous-load limit
+    pub above_rating: Option<f32>,
+    pub for_secs: Option<u64>,
+    pub hysteresis: Option<f32>,
+}
+
+impl AlertRule {
+    pub fn applies_to(&self, device: &PZEMDevice) -> bool {
+        self.meters.as_ref().map_or(true, |m| m.contains(&device.breaker))
+            && (self.above_for(device).is_some() || self.below.is_some())
+    }
+
+    // the upper limit on `device`: the tighter of `above` and `above_rating`
+    pub fn above_for(&self, device: &PZEMDevice) -> Option<f32> {
+        let from_rating = self
+            .above_rating
+            .zip(device.rating_amps)
+            .map(|(pct, rating)| rating as f32 * pct / 100.0);
+        match (self.above, from_rating) {
+            (Some(a), Some(r)) => Some(a.min(r)),
+            (a, r) => a.or(r),
+        }
+    }
+}
+
+// mains minus the metered branches, i.e. whatever load isn't on a branch meter
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct RemainderMeter {
+    pub name: String,
+    pub mains: String,
+    // defaults to every device except the mains
+    pub branches: Option<Vec<String>>,
+    pub stale_after: Option<u64>,
+}
+
+impl RemainderMeter {
+    pub fn stale_after(&self) -> u64 {
+        self.stale_after.unwrap_or(GROUP_STALE_AFTER_SECS)
+    }
+
+    pub fn branches(&self, config: &AppConfig) -> Vec<String> {
+        match &self.branches {
+            Some(b) => b.clone(),
+            None => config
+                .devices()
+                .iter()
+                .filter(|d| d.breaker != self.mains)
+                .map(|d| d.breaker.clone())
+                .collect(),
+        }
+    }
+}
+
+impl PZEMDevice {
+    // modbus addresses are only unique per port, so both are needed to identify a meter
+    pub fn key(&self) -> String {
+        format!("{}/{}", self.port, self.addr)
+    }
+}
+
+impl AppConfig {
+    pub fn load(path: &str) -> Result<AppConfig, ConfigError> {
+        let yaml = fs::read_to_string(path)
+            .map_err(|e| ConfigError::Read(format!("{path}: {e}")))?;
+        let cfg: AppConfig = serde_yaml::from_str(&yaml)
+            .map_err(|e| ConfigError::Parse(e.to_string()))?;
+        cfg.validate()?;
+        Ok(cfg)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        // without a broker the bridge can still record to its other sinks
+        if self.mqtt_server_addr.is_empty() && self.mqtt_sink_enabled() {
+            return Err(ConfigError::Invalid("mqtt_server_addr is empty; disable mqtt_sink to run without a broker".to_string()));
+        }
+        for output in self.mqtt_outputs.iter().flatten() {
+            if self.mqtt_server_addr.is_empty() {
+                return Err(ConfigError::Invalid("mqtt_outputs need mqtt_server_addr".to_string()));
+            }
+            let topic = output.base_topic();
+            if topic.is_empty() || topic.contains(['+', '#']) || topic.ends_with('/') {
+                return Err(ConfigError::Invalid(format!("mqtt_outputs: {topic:?} is not a valid base_topic")));
+            }
+            if output.group_id().is_empty() || output.group_id().contains(['/', '+', '#']) {
+                return Err(ConfigError::Invalid("mqtt_outputs: group_id must be a single topic level".to_string()));
+            }
+        }
+        // the edge node's client id and bdSeq aren't per output
+        if self.mqtt_outputs.iter().flatten().filter(|o| o.format == MqttFormat::Sparkplug).count() > 1 {
+            return Err(ConfigError::Invalid("mqtt_outputs: only one sparkplug output is supported".to_string()));
+        }
+        let mut seen: HashSet<String> = HashSet::new();
+        for device in self.devices() {
+            if device.addr == 0 || device.addr > 247 {
+                return Err(ConfigError::Invalid(format!(
+                    "device {} has modbus address {} outside 1-247",
+                    device.breaker, device.addr
+                )));
+            }
+            if device.port.is_empty() {
+                return Err(ConfigError::Invalid(format!("device {} has no port", device.addr)));
+            }
+            if device.rating_amps == Some(0) || device.circuit_volts == Some(0) {
+                return Err(ConfigError::Invalid(format!("device {}: rating_amps and circuit_volts must be positive", device.breaker)));
+            }
+            let mut detector_names: HashSet<&str> = HashSet::new();
+            for detector in device.state_detectors() {
+                if detector.name.is_empty() {
+                    return Err(ConfigError::Invalid(format!("device {}: state detector with an empty name", device.breaker)));
+                }
+                if !detector_names.insert(detector.name.as_str()) {
+                    return Err(ConfigError::Invalid(format!(
+                        "device {}: state detector {} is listed twice",
+                        device.breaker, detector.name
+                    )));
+                }
+                if detector.off_watts() > detector.on_watts {
+                    return Err(ConfigError::Invalid(format!(
+                        "device {}: state detector {} has off_watts above on_watts",
+                        device.breaker, detector.name
+                    )));
+                }
+                let mut band_names: HashSet<&str> = HashSet::new();
+                for band in detector.bands() {
+                    if band.name.is_empty() || band.name == "off" || !band_names.insert(band.name.as_str()) {
+                        return Err(ConfigError::Invalid(format!(
+                            "device {}: state detector {} has an empty, duplicate or reserved band name {:?}",
+                            device.breaker, detector.name, band.name
+                        )));
+                    }
+                }
+            }
+            if !seen.insert(device.key()) {
+                return Err(ConfigError::Invalid(format!("device {} is listed twice", device.key())));
+            }
+        }
+        let mut group_names: HashSet<&str> = HashSet::new();
+        for group in self.groups() {
+            if group.name.is_empty() {
+                return Err(ConfigError::Invalid("group with an empty name".to_string()));
+            }
+            if !group_names.insert(group.name.as_str()) {
+                return Err(ConfigError::Invalid(format!("group {} is listed twice", group.name)));
+            }
+            if group.members.is_empty() {
+                return Err(ConfigError::Invalid(format!("group {} has no members", group.name)));
+            }
+            let mut members: HashSet<&str> = HashSet::new();
+            for member in group.members.iter() {
+                self.device_by_breaker(member).map_err(|e| {
+                    ConfigError::Invalid(format!("group {}: {e}", group.name))
+                })?;
+                if !members.insert(member.as_str()) {
+                    return Err(ConfigError::Invalid(format!("group {}: {member} is listed twice", group.name)));
+                }
+            }
+        }
+        let mut remainder_names: HashSet<&str> = HashSet::new();
+        for remainder in self.remainders() {
+            if remainder.name.is_empty() {
+                return Err(ConfigError::Invalid("remainder with an empty name".to_string()));
+            }
+            if !remainder_names.insert(remainder.name.as_str()) {
+                return Err(ConfigError::Invalid(format!("remainder {} is listed twice", remainder.name)));
+            }
+            self.device_by_breaker(&remainder.mains).map_err(|e| {
+                ConfigError::Invalid(format!("remainder {}: {e}", remainder.name))
+            })?;
+            let mut branches: HashSet<&str> = HashSet::new();
+            let remainder_branches = remainder.branches(self);
+            for branch in remainder_branches.iter() {
+                if !branches.insert(branch.as_str()) {
+                    return Err(ConfigError::Invalid(format!("remainder {}: {branch} is listed twice", remainder.name)));
+                }
+                if *branch == remainder.mains {
+                    return Err(ConfigError::Invalid(format!(
+                        "remainder {}: mains {} is also listed as a branch",
+                        remainder.name, branch
+                    )));
+                }
+                self.device_by_breaker(branch).map_err(|e| {
+                    ConfigError::Invalid(format!("remainder {}: {e}", remainder.name))
+                })?;
+            }
+        }
+        if let Some(periods) = &self.energy_periods {
+            periods.timezone().map_err(|e| ConfigError::Invalid(format!("energy_periods: {e}")))?;
+            periods.reset_time().map_err(|e| ConfigError::Invalid(format!("energy_periods: {e}")))?;
+            periods.week_start().map_err(|e| ConfigError::Invalid(format!("energy_periods: {e}")))?;
+        }
+        if let Some(tariffs) = &self.tariffs {
+            if tariffs.currency.is_empty() {
+                return Err(ConfigError::Invalid("tariffs: currency is empty".to_string()));
+            }
+            if tariffs.periods.is_empty() {
+                return Err(ConfigError::Invalid("tariffs: no periods".to_string()));
+            }
+            tariffs.timezone(self).map_err(|e| ConfigError::Invalid(format!("tariffs: {e}")))?;
+            for period in tariffs.periods.iter() {
+                period.days().map_err(ConfigError::Invalid)?;
+                period.start().map_err(ConfigError::Invalid)?;
+                period.end().map_err(ConfigError::Invalid)?;
+            }
+            let schedule = TariffSchedule::new(tariffs, self).map_err(|e| ConfigError::Invalid(format!("tariffs: {e}")))?;
+            if let Some((day, time)) = schedule.uncovered() {
+                return Err(ConfigError::Invalid(format!(
+                    "tariffs: no period covers {day} {}, add a catch-all period last",
+                    time.format("%H:%M")
+                )));
+            }
+            // a charge has to be billed to someone; there's no meter it could sensibly default to
+            if tariffs.fixed_daily_charge.is_some() && tariffs.fixed_charge_to.is_none() {
+                return Err(ConfigError::Invalid("tariffs: fixed_daily_charge needs fixed_charge_to".to_string()));
+            }
+            if let Some(to) = &tariffs.fixed_charge_to {
+                if self.device_by_breaker(to).is_err() && !self.groups().iter().any(|g| &g.name == to) {
+                    return Err(ConfigError::Invalid(format!("tariffs: fixed_charge_to {to} is not a breaker or group")));
+                }
+            }
+        }
+        if let Some(pq) = &self.power_quality {
+            let windows = pq.windows_secs();
+            if windows.is_empty() || windows.contains(&0) {
+                return Err(ConfigError::Invalid("power_quality: windows_secs must be non-empty and positive".to_string()));
+            }
+            if windows.iter().collect::<HashSet<_>>().len() != windows.len() {
+                return Err(ConfigError::Invalid("power_quality: windows_secs has duplicates".to_string()));
+            }
+            if pq.rearm_volts() < 0.0 {
+                return Err(ConfigError::Invalid("power_quality: rearm_volts can't be negative".to_string()));
+            }
+            if let (Some(sag), Some(swell)) = (pq.sag_below_volts, pq.swell_above_volts) {
+                if sag >= swell {
+                    return Err(ConfigError::Invalid("power_quality: sag_below_volts must be less than swell_above_volts".to_string()));
+                }
+            }
+        }
+        if let Some(history) = &self.history {
+            if history.raw_retention_days() == 0 || history.rollup_retention_days() < history.raw_retention_days() {
+                return Err(ConfigError::Invalid(
+                    "history: raw_retention_days must be positive and no longer than rollup_retention_days".to_string(),
+                ));
+            }
+        }
+        if let Some(influx) = &self.influx {
+            if influx.url.is_none() && influx.udp_addr.is_none() && influx.line_file.is_none() {
+                return Err(ConfigError::Invalid("influx needs a url, udp_addr or line_file".to_string()));
+            }
+            if influx.url.is_some() && (influx.org.is_none() || influx.bucket.is_none()) {
+                return Err(ConfigError::Invalid("influx: the v2 write api needs org and bucket".to_string()));
+            }
+            if influx.batch_size() == 0 || influx.flush_interval_secs() == 0 || influx.timeout_secs() == 0 {
+                return Err(ConfigError::Invalid("influx: batch_size, flush_interval_secs and timeout_secs must be positive".to_string()));
+            }
+        }
+        if let Some(file) = &self.file_sink {
+            if file.directory.is_empty() {
+                return Err(ConfigError::Invalid("file_sink has no directory".to_string()));
+            }
+            if file.prefix().is_empty() || file.prefix().contains(['/', '\\']) {
+                return Err(ConfigError::Invalid("file_sink: prefix must be a plain file name".to_string()));
+            }
+            if file.max_total_mb == Some(0) {
+                return Err(ConfigError::Invalid("file_sink: max_total_mb must be positive".to_string()));
+            }
+        }
+        if let Some(graphite) = &self.graphite {
+            if graphite.addr.is_empty() {
+                return Err(ConfigError::Invalid("graphite has no addr".to_string()));
+            }
+            if graphite.flush_interval_secs() == 0 {
+                return Err(ConfigError::Invalid("graphite: flush_interval_secs must be positive".to_string()));
+            }
+            // metric paths are named after the breaker, so two meters mustn't end up at the same one
+            let mut nodes = HashSet::new();
+            for device in self.devices() {
+                if !nodes.insert(path_node(&device.breaker)) {
+                    return Err(ConfigError::Invalid(format!(
+                        "graphite: breaker {} has the same metric path as another meter",
+                        device.breaker
+                    )));
+                }
+            }
+        }
+        if let Some(webhook) = &self.webhook {
+            if webhook.url.is_empty() {
+                return Err(ConfigError::Invalid("webhook has no url".to_string()));
+            }
+            for event in webhook.events.iter().flatten() {
+                if !WEBHOOK_EVENTS.contains(&event.as_str()) {
+                    return Err(ConfigError::Invalid(format!(
+                        "webhook: unknown event {event}, expected one of {}",
+                        WEBHOOK_EVENTS.join(", ")
+                    )));
+                }
+            }
+            if webhook.timeout_secs() == 0 {
+                return Err(ConfigError::Invalid("webhook: timeout_secs must be positive".to_string()));
+            }
+        }
+        let queue_sizes = [
+            self.mqtt_sink.as_ref().and_then(|s| s.queue_size),
+            self.history.as_ref().and_then(|s| s.queue_size),
+            self.influx.as_ref().and_then(|s| s.queue_size),
+            self.prometheus.as_ref().and_then(|s| s.queue_size),
+            self.file_sink.as_ref().and_then(|s| s.queue_size),
+            self.graphite.as_ref().and_then(|s| s.queue_size),
+            self.webhook.as_ref().and_then(|s| s.queue_size),
+        ];
+        let queue_sizes: Vec<Option<usize>> = queue_sizes
+            .into_iter()
+            .chain(self.mqtt_outputs.iter().flatten().map(|o| o.queue_size))
+            .collect();
+        if queue_sizes.contains(&Some(0)) {
+            return Err(ConfigError::Invalid("sink queue_size must be positive".to_string()));
+        }
+        let mut rule_names: HashSet<&str> = HashSet::new();
+        for rule in self.rules() {
+            if rule.name.is_empty() {
+                return Err(ConfigError::Invalid("rule with an empty name".to_string()));
+            }
+            if !rule_names.insert(rule.name.as_str()) {
+                return Err(ConfigError::Invalid(format!("rule {} is listed twice", rule.name)));
+            }
+            if !FIELDS.contains(&rule.metric.as_str()) {
+                return Err(ConfigError::Invalid(format!("rule {}: unknown metric {}", rule.name, rule.metric)));
+            }
+            if let Some(pct) = rule.above_rating {
+                if rule.metric != "amps" {
+                    return Err(ConfigError::Invalid(format!("rule {}: above_rating only applies to amps", rule.name)));
+                }
+                if pct <= 0.0 {
+                    return Err(ConfigError::Invalid(format!("rule {}: above_rating must be positive", rule.name)));
+                }
+            }
+            if rule.hysteresis.is_some_and(|h| h < 0.0) {
+                return Err(ConfigError::Invalid(format!("rule {}: hysteresis can't be negative", rule.name)));
+            }
+            match (rule.above, rule.below) {
+                (None, None) if rule.above_rating.is_none() => {
+                    return Err(ConfigError::Invalid(format!("rule {} needs above and/or below", rule.name)));
+                }
+                (Some(a), Some(b)) if b >= a => {
+                    return Err(ConfigError::Invalid(format!("rule {}: below must be less than above", rule.name)));
+                }
+                _ => {}
+            }
+            for meter in rule.meters.iter().flatten() {
+                self.device_by_breaker(meter).map_err(|e| {
+                    ConfigError::Invalid(format!("rule {}: {e}", rule.name))
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    // breaker names are how groups refer to devices, so they have to resolve to exactly one
+    pub fn device_by_breaker(&self, breaker: &str) -> Result<&PZEMDevice, String> {
+        let mut matches = self.devices().iter().filter(|d| d.breaker == breaker);
+        match (matches.next(), matches.next()) {
+            (Some(d), None) => Ok(d),
+            (None, _) => Err(format!("no device with breaker {breaker}")),
+            (Some(_), Some(_)) => Err(format!("more than one device with breaker {breaker}")),
+        }
+    }
+
+    pub fn groups(&self) -> &[MeterGroup] {
+        self.groups.as_deref().unwrap_or_default()
+    }
+
+    pub fn rules(&self) -> &[AlertRule] {
+        self.rules.as_deref().unwrap_or_default()
+    }
+
+    pub fn remainders(&self) -> &[RemainderMeter] {
+        self.remainders.as_deref().unwrap_or_default()
+    }
+
+    pub fn devices(&self) -> &[PZEMDevice] {
+        self.devices.as_deref().unwrap_or_default()
+    }
+
+    pub fn client_id(&self) -> String {
+        self.mqtt_client_id.clone().unwrap_or("pzem016mqtt".to_string())
+    }
+
+    pub fn http_listen_addr(&self) -> &str {
+        self.http_listen_addr.as_deref().unwrap_or(DEFAULT_HTTP_LISTEN_ADDR)
+    }
+
+    pub fn mqtt_enabled(&self) -> bool {
+        !self.mqtt_server_addr.is_empty()
+    }
+
+    pub fn mqtt_sink_enabled(&self) -> bool {
+        self.mqtt_sink.as_ref().and_then(|s| s.enabled).unwrap_or(true)
+    }
+
+    // true if the set of sinks or their settings changed; sinks are only started at startup
+    pub fn sinks_changed(&self, other: &AppConfig) -> bool {
+        self.mqtt_sink != other.mqtt_sink
+            || self.mqtt_outputs != other.mqtt_outputs
+            || self.history != other.history
+            || self.influx != other.influx
+            || self.prometheus != other.prometheus
+            || self.file_sink != other.file_sink
+            || self.graphite != other.graphite
+            || self.webhook != other.webhook
+    }
+
+    // where calendar dates in the http api are interpreted
+    pub fn local_timezone(&self) -> Tz {
+        let tz = match (&self.energy_periods, &self.tariffs) {
+            (Some(periods), _) => periods.timezone(),
+            (None, Some(tariffs)) => tariffs.timezone(self),
+            (None, None) => Ok(Tz::UTC),
+        };
+        tz.unwrap_or(Tz::UTC)
+    }
+
+    pub fn state_dir(&self) -> &str {
+        self.state_dir.as_deref().unwrap_or(DEFAULT_STATE_DIR)
+    }
+
+    pub fn json_state(&self) -> bool {
+        self.json_state.unwrap_or(false)
+    }
+
+    pub fn energy_wrap_wh(&self) -> f32 {
+        self.energy_wrap_wh.unwrap_or(ENERGY_REGISTER_WRAP_WH)
+    }
+
+    pub fn timestamp_format(&self) -> TimestampFormat {
+        self.timestamp_format.unwrap_or_default()
+    }
+
+    // true if anything the pollers read when they start has changed
+    pub fn poller_settings_changed(&self, other: &AppConfig) -> bool {
+        self.energy_wrap_wh != other.energy_wrap_wh
+            || self.tariffs != other.tariffs
+            || self.rules != other.rules
+            || self.power_quality != other.power_quality
+            || self.energy_periods != other.energy_periods
+            || self.timestamp_format != other.timestamp_format
+    }
+
+    // true if anything that requires a new mqtt connection has changed
+    pub fn mqtt_changed(&self, other: &AppConfig) -> bool {
+        self.mqtt_server_addr != other.mqtt_server_addr
+            || self.mqtt_server_port != other.mqtt_server_port
+            || self.mqtt_client_id != other.mqtt_client_id
+            || self.mqtt_username != other.mqtt_username
+            || self.mqtt_password != other.mqtt_password
+    }
+}
+
+pub fn config_file_path() -> String {
+    match std::env::var("CONFIG_FILE_PATH") {
+        Ok(s) => s,
+        Err(_e) => { "./config.yaml".to_string()}
+    }
 }
@@ -5,3 +5,27 @@ pub const MQTT_POLL_INTERVAL_MILLIS: u64 = 50_u64;
 
 pub const MPSC_BUFFER_SIZE: usize = 512_usize;
 pub const POLL_TIME: u16 = 5_u16;
+pub const CONFIG_RELOAD_DEBOUNCE_MILLIS: u64 = 500_u64;
+pub const DEFAULT_STATE_DIR: &str = "./state";
+pub const DEFAULT_HTTP_LISTEN_ADDR: &str = "0.0.0.0:9898";
+pub const GROUP_STALE_AFTER_SECS: u64 = 60_u64;
+// consecutive failed reads before a meter is reported offline
+pub const METER_OFFLINE_AFTER_FAILURES: u32 = 3_u32;
+// the PZEM-016 clears its energy register after 9999.99kWh
+pub const ENERGY_REGISTER_WRAP_WH: f32 = 10_000_000_f32;
+pub const POWER_QUALITY_WINDOWS_SECS: [u64; 2] = [60_u64, 900_u64];
+pub const POWER_QUALITY_REARM_VOLTS: f32 = 2_f32;
+pub const HISTORY_RAW_RETENTION_DAYS: u32 = 7_u32;
+pub const HISTORY_ROLLUP_RETENTION_DAYS: u32 = 365_u32;
+pub const INFLUX_BATCH_SIZE: usize = 500_usize;
+pub const INFLUX_FLUSH_INTERVAL_SECS: u64 = 10_u64;
+pub const INFLUX_MAX_RETRIES: u32 = 5_u32;
+pub const INFLUX_TIMEOUT_SECS: u64 = 10_u64;
+pub const GRAPHITE_PREFIX: &str = "pzem016";
+pub const GRAPHITE_FLUSH_INTERVAL_SECS: u64 = 10_u64;
+pub const WEBHOOK_EVENTS: [&str; 5] = ["meter_offline", "meter_online", "energy_reset", "rule_triggered", "rule_cleared"];
+pub const WEBHOOK_MAX_RETRIES: u32 = 5_u32;
+pub const WEBHOOK_TIMEOUT_SECS: u64 = 10_u64;
+// readings waiting for a sink; beyond this new readings are dropped for that sink only
+pub const SINK_QUEUE_SIZE: usize = 1024_usize;
+pub const SINK_STOP_TIMEOUT_SECS: u64 = 5_u64;
@@ -0,0 +1,194 @@
+use crate::config::{PZEMDevice, StateDetector};
+use crate::events::BridgeEvent;
+use crate::groups::slug;
+use crate::payload::{DeviceInfo, HAConfigPayload};
+use crate::timestamp::Timestamp;
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+
+const OFF_BAND: &str = "off";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DetectorStatePayload {
+    pub state: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub band: Option<String>,
+    // how long the current cycle has been running
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub running_secs: Option<u64>,
+    pub last_seen: Timestamp,
+}
+
+struct TrackedDetector {
+    detector: StateDetector,
+    bands: Vec<(String, u32)>,
+    on: bool,
+    // when the reading first disagreed with `on`, and the energy counter at that point
+    pending: Option<(Instant, f64)>,
+    // start of the current cycle, and the energy counter at that point
+    cycle_start: Option<(Instant, f64)>,
+}
+
+impl TrackedDetector {
+    fn band(&self, watts: f32) -> Option<String> {
+        if self.bands.is_empty() {
+            return None;
+        }
+        if !self.on {
+            return Some(OFF_BAND.to_string());
+        }
+        // below the lowest band still counts as the lowest band
+        let band = self
+            .bands
+            .iter()
+            .rev()
+            .find(|(_, min)| watts >= *min as f32)
+            .unwrap_or(&self.bands[0]);
+        Some(band.0.clone())
+    }
+}
+
+// the state detectors configured on a single meter
+pub struct DetectorSet {
+    device: PZEMDevice,
+    detectors: Vec<TrackedDetector>,
+}
+
+impl DetectorSet {
+    pub fn new(device: &PZEMDevice) -> Self {
+        DetectorSet {
+            device: device.clone(),
+            detectors: device
+                .state_detectors()
+                .iter()
+                .map(|d| {
+                    let mut bands: Vec<(String, u32)> = d.bands().iter().map(|b| (b.name.clone(), b.min_watts)).collect();
+                    bands.sort_by_key(|(_, min)| *min);
+                    TrackedDetector {
+                        detector: d.clone(),
+                        bands,
+                        on: false,
+                        pending: None,
+                        cycle_start: None,
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    // `energy_wh` is the meter's continuous energy counter; returns a CycleComplete event for
+    // every detector that turned off on this reading
+    pub fn update(&mut self, watts: f32, energy_wh: f64, now: Instant) -> Vec<BridgeEvent> {
+        let mut events: Vec<BridgeEvent> = vec![];
+        for tracked in self.detectors.iter_mut() {
+            let want_on = if tracked.on {
+                watts >= tracked.detector.off_watts() as f32
+            } else {
+                watts > tracked.detector.on_watts as f32
+            };
+            if want_on == tracked.on {
+                tracked.pending = None;
+                continue;
+            }
+            let (since, since_wh) = *tracked.pending.get_or_insert((now, energy_wh));
+            if now.duration_since(since).as_secs() < tracked.detector.debounce_secs.unwrap_or(0) {
+                continue;
+            }
+            tracked.on = want_on;
+            tracked.pending = None;
+            if want_on {
+                tracked.cycle_start = Some((since, since_wh));
+            } else if let Some((start, start_wh)) = tracked.cycle_start.take() {
+                events.push(BridgeEvent::CycleComplete {
+                    detector: tracked.detector.name.clone(),
+                    meter: self.device.key(),
+                    breaker: self.device.breaker.clone(),
+                    duration_secs: since.duration_since(start).as_secs(),
+                    energy_wh: (since_wh - start_wh).max(0.0),
+                });
+            }
+        }
+        events
+    }
+
+    // (detector name, state) for every detector on this meter
+    pub fn payloads(&self, watts: f32, now: Instant, last_seen: Timestamp) -> Vec<(&str, DetectorStatePayload)> {
+        self.detectors
+            .iter()
+            .map(|t| {
+                let payload = DetectorStatePayload {
+                    state: if t.on { "ON" } else { "OFF" }.to_string(),
+                    band: t.band(watts),
+                    running_secs: t.cycle_start.map(|(start, _)| now.duration_since(start).as_secs()),
+                    last_seen: last_seen.clone(),
+                };
+                (t.detector.name.as_str(), payload)
+            })
+            .collect()
+    }
+}
+
+pub fn detector_state_topic(serial: &str, detector: &str) -> String {
+    format!("pzem016mqtt/pzem016-{serial}/detector_{}", slug(detector))
+}
+
+fn running_config_topic(serial: &str, detector: &str) -> String {
+    format!("homeassistant/binary_sensor/pzem016-{serial}/detector_{}/config", slug(detector))
+}
+
+fn band_config_topic(serial: &str, detector: &str) -> String {
+    format!("homeassistant/sensor/pzem016-{serial}/detector_{}_band/config", slug(detector))
+}
+
+pub fn detector_discovery_topics(serial: &str, device: &PZEMDevice) -> Vec<String> {
+    let mut topics: Vec<String> = vec![];
+    for d in device.state_detectors() {
+        topics.push(running_config_topic(serial, &d.name));
+        if !d.bands().is_empty() {
+            topics.push(band_config_topic(serial, &d.name));
+        }
+    }
+    topics
+}
+
+pub fn detector_discovery_payloads(
+    serial: &str,
+    unit_name: &str,
+    device: &PZEMDevice,
+    device_info: &DeviceInfo,
+) -> Vec<(String, HAConfigPayload)> {
+    let mut payloads: Vec<(String, HAConfigPayload)> = vec![];
+    for d in device.state_detectors() {
+        let name = format!("{unit_name}-detector_{}", slug(&d.name));
+        let running = HAConfigPayload {
+            name: name.clone(),
+            unique_id: name.clone(),
+            device: device_info.clone(),
+            state_topic: detector_state_topic(serial, &d.name),
+            device_class: Some("running".to_string()),
+            value_template: Some("{{ value_json.state }}".to_string()),
+            json_attributes_topic: Some(detector_state_topic(serial, &d.name)),
+            payload_on: Some("ON".to_string()),
+            payload_off: Some("OFF".to_string()),
+            ..Default::default()
+        };
+        payloads.push((running_config_topic(serial, &d.name), running));
+        if d.bands().is_empty() {
+            continue;
+        }
+        let mut options: Vec<String> = vec![OFF_BAND.to_string()];
+        options.extend(d.bands().iter().map(|b| b.name.clone()));
+        let band = HAConfigPayload {
+            name: format!("{name}_band"),
+            unique_id: format!("{name}_band"),
+            device: device_info.clone(),
+            state_topic: detector_state_topic(serial, &d.name),
+            device_class: Some("enum".to_string()),
+            options: Some(options),
+            value_template: Some("{{ value_json.band }}".to_string()),
+            ..Default::default()
+        };
+        payloads.push((band_config_topic(serial, &d.name), band));
+    }
+    payloads
+}
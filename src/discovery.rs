@@ -0,0 +1,55 @@
+use crate::ipc::IPCMessage;
+use crate::payload::{publish, Payload};
+use crate::state::{load_json, save_json};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+
+const REGISTRY_FILE: &str = "discovery_topics.json";
+
+// Every HA discovery topic we have published a retained config to, persisted so that
+// entities belonging to meters removed while the bridge was down can still be cleaned up.
+pub struct DiscoveryRegistry {
+    path: PathBuf,
+    topics: BTreeSet<String>,
+}
+
+impl DiscoveryRegistry {
+    pub fn load(state_dir: &str) -> Self {
+        let path = Path::new(state_dir).join(REGISTRY_FILE);
+        let topics = load_json::<BTreeSet<String>>(&path).unwrap_or_default();
+        DiscoveryRegistry { path, topics }
+    }
+
+    pub fn record(&mut self, topic: &str) {
+        if self.topics.insert(topic.to_string()) {
+            self.save();
+        }
+    }
+
+    // topics that may have been announced without being recorded, e.g. by an older version
+    pub fn adopt(&mut self, topics: BTreeSet<String>) {
+        self.topics.extend(topics);
+    }
+
+    // clear every announced topic that is not in `expected`
+    pub async fn prune(&mut self, expected: &BTreeSet<String>, tx: &mpsc::Sender<IPCMessage>) {
+        let stale: Vec<String> = self.topics.difference(expected).cloned().collect();
+        if stale.is_empty() {
+            return;
+        }
+        for topic in stale {
+            info!("Removing stale discovery topic {topic}");
+            if let Err(e) = publish(tx, topic.clone(), Payload::None, true).await {
+                error!("Couldn't clear discovery topic {topic}: {e}");
+                continue;
+            }
+            self.topics.remove(&topic);
+        }
+        self.save();
+    }
+
+    fn save(&self) {
+        save_json(&self.path, &self.topics);
+    }
+}
@@ -0,0 +1,187 @@
+use crate::config::EnergyPeriods;
+use crate::groups::slug;
+use crate::payload::{config_topic, DeviceInfo, HAConfigPayload};
+use crate::state::{load_json, save_json};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveTime, SecondsFormat, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+const PERIODS: [&str; 4] = ["today", "yesterday", "week", "month"];
+const SAVE_INTERVAL_SECS: u64 = 60;
+
+pub struct PeriodSchedule {
+    tz: Tz,
+    reset_time: NaiveTime,
+    week_start: Weekday,
+}
+
+impl PeriodSchedule {
+    pub fn new(periods: &EnergyPeriods) -> Result<Self, String> {
+        Ok(PeriodSchedule {
+            tz: periods.timezone()?,
+            reset_time: periods.reset_time()?,
+            week_start: periods.week_start()?,
+        })
+    }
+
+    // calendar days in `tz`, for when no energy_periods are configured
+    pub fn daily(tz: Tz) -> Self {
+        PeriodSchedule {
+            tz,
+            reset_time: NaiveTime::MIN,
+            week_start: Weekday::Mon,
+        }
+    }
+
+    // the local date whose period contains `t`; before reset_time that is still yesterday
+    pub fn day_of(&self, t: DateTime<Utc>) -> NaiveDate {
+        let local = t.with_timezone(&self.tz).naive_local();
+        let day = local.date();
+        if local.time() < self.reset_time {
+            day.pred_opt().unwrap_or(day)
+        } else {
+            day
+        }
+    }
+
+    pub fn week_of(&self, day: NaiveDate) -> NaiveDate {
+        let offset = (7 + day.weekday().num_days_from_monday() - self.week_start.num_days_from_monday()) % 7;
+        day - Duration::days(offset as i64)
+    }
+
+    pub fn month_of(&self, day: NaiveDate) -> NaiveDate {
+        day.with_day(1).unwrap_or(day)
+    }
+
+    pub fn start_of(&self, day: NaiveDate) -> String {
+        let start = self
+            .tz
+            .from_local_datetime(&day.and_time(self.reset_time))
+            .earliest()
+            .map(|d| d.with_timezone(&Utc))
+            .unwrap_or_else(|| Utc.from_utc_datetime(&day.and_time(self.reset_time)));
+        start.to_rfc3339_opts(SecondsFormat::Secs, true)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PeriodCounters {
+    pub day: Option<NaiveDate>,
+    pub last_total_wh: Option<f64>,
+    pub today_wh: f64,
+    pub yesterday_wh: f64,
+    pub week_wh: f64,
+    pub month_wh: f64,
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    last_save: Option<Instant>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PeriodEnergyPayload {
+    pub today: f64,
+    pub yesterday: f64,
+    pub week: f64,
+    pub month: f64,
+    pub today_reset: String,
+    pub yesterday_reset: String,
+    pub week_reset: String,
+    pub month_reset: String,
+}
+
+impl PeriodCounters {
+    pub fn load(state_dir: &str, meter_key: &str) -> Self {
+        let path = Path::new(state_dir).join("energy_periods").join(format!("{}.json", slug(meter_key)));
+        let mut counters = load_json::<PeriodCounters>(&path).unwrap_or_default();
+        counters.path = path;
+        counters
+    }
+
+    // `total_wh` is the meter's continuous energy counter; only increases are accumulated
+    pub fn update(&mut self, total_wh: f64, now: DateTime<Utc>, schedule: &PeriodSchedule) {
+        let day = schedule.day_of(now);
+        let mut rolled_over = false;
+        if let Some(prev) = self.day {
+            if prev != day {
+                self.yesterday_wh = if prev.succ_opt() == Some(day) { self.today_wh } else { 0.0 };
+                self.today_wh = 0.0;
+                if schedule.week_of(prev) != schedule.week_of(day) {
+                    self.week_wh = 0.0;
+                }
+                if schedule.month_of(prev) != schedule.month_of(day) {
+                    self.month_wh = 0.0;
+                }
+                rolled_over = true;
+            }
+        }
+        self.day = Some(day);
+
+        if let Some(last) = self.last_total_wh {
+            let delta = total_wh - last;
+            if delta > 0.0 {
+                self.today_wh += delta;
+                self.week_wh += delta;
+                self.month_wh += delta;
+            }
+        }
+        self.last_total_wh = Some(total_wh);
+
+        if rolled_over || self.last_save.map_or(true, |t| t.elapsed().as_secs() >= SAVE_INTERVAL_SECS) {
+            self.save();
+        }
+    }
+
+    pub fn payload(&self, schedule: &PeriodSchedule) -> Option<PeriodEnergyPayload> {
+        let day = self.day?;
+        Some(PeriodEnergyPayload {
+            today: self.today_wh,
+            yesterday: self.yesterday_wh,
+            week: self.week_wh,
+            month: self.month_wh,
+            today_reset: schedule.start_of(day),
+            yesterday_reset: schedule.start_of(day.pred_opt().unwrap_or(day)),
+            week_reset: schedule.start_of(schedule.week_of(day)),
+            month_reset: schedule.start_of(schedule.month_of(day)),
+        })
+    }
+
+    pub fn save(&mut self) {
+        self.last_save = Some(Instant::now());
+        save_json(&self.path, &self);
+    }
+}
+
+pub fn period_state_topic(serial: &str) -> String {
+    format!("pzem016mqtt/pzem016-{serial}/energy_periods")
+}
+
+pub fn period_discovery_topics(serial: &str) -> Vec<String> {
+    PERIODS.iter().map(|p| config_topic(serial, &format!("energy_{p}"))).collect()
+}
+
+pub fn period_discovery_payloads(serial: &str, unit_name: &str, device_info: &DeviceInfo) -> Vec<(String, HAConfigPayload)> {
+    PERIODS
+        .iter()
+        .map(|p| {
+            let metric = format!("energy_{p}");
+            let name = format!("{unit_name}-{metric}");
+            let config_payload = HAConfigPayload {
+                name: name.clone(),
+                unique_id: name,
+                device: device_info.clone(),
+                state_topic: period_state_topic(serial),
+                device_class: Some("energy".to_string()),
+                state_class: Some("total".to_string()),
+                native_uom: Some("Wh".to_string()),
+                suggested_display_precision: Some(0),
+                value_template: Some(format!("{{{{ value_json.{p} }}}}")),
+                last_reset_value_template: Some(format!("{{{{ value_json.{p}_reset }}}}")),
+                ..Default::default()
+            };
+            (config_topic(serial, &metric), config_payload)
+        })
+        .collect()
+}
@@ -0,0 +1,155 @@
+use crate::events::EnergyResetKind;
+use crate::groups::slug;
+use crate::payload::{config_topic, DeviceInfo, HAConfigPayload};
+use crate::state::{load_json, save_json};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tokio::sync::RwLock;
+
+pub const CONTINUOUS_ENERGY_METRIC: &str = "energy_continuous";
+const SAVE_INTERVAL_SECS: u64 = 60;
+// how close to the top (and bottom) of the register a drop has to be to count as a wrap
+const WRAP_MARGIN: f32 = 0.1;
+
+lazy_static! {
+    // meters whose running total restarts from zero at their next reading, by PZEMDevice::key()
+    pub static ref ENERGY_RESET_REQUESTS: RwLock<HashSet<String>> = RwLock::new(HashSet::new());
+}
+
+// Turns the meter's energy register, which wraps and can be cleared, into a counter that only
+// ever goes up.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct EnergyTracker {
+    pub last_raw_wh: Option<f32>,
+    pub continuous_wh: f64,
+    // continuous_wh as it was at the last requested reset; the total published over mqtt counts
+    // from here, while continuous_wh itself never goes back so differences of it stay valid
+    #[serde(default)]
+    pub reset_offset_wh: f64,
+    pub resets: u64,
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    last_save: Option<Instant>,
+}
+
+impl EnergyTracker {
+    pub fn load(state_dir: &str, meter_key: &str) -> Self {
+        let path = Path::new(state_dir).join("energy").join(format!("{}.json", slug(meter_key)));
+        let mut tracker = load_json::<EnergyTracker>(&path).unwrap_or_default();
+        tracker.path = path;
+        tracker
+    }
+
+    pub fn update(&mut self, raw_wh: f32, wrap_wh: f32) -> Option<EnergyResetKind> {
+        let detected = match self.last_raw_wh {
+            // first reading ever: carry on from whatever the meter already counted
+            None => {
+                self.continuous_wh = raw_wh as f64;
+                None
+            }
+            Some(last) if raw_wh >= last => {
+                self.continuous_wh += (raw_wh - last) as f64;
+                None
+            }
+            Some(last) => {
+                if last >= wrap_wh * (1.0 - WRAP_MARGIN) && raw_wh <= wrap_wh * WRAP_MARGIN {
+                    self.continuous_wh += ((wrap_wh - last) + raw_wh) as f64;
+                    Some(EnergyResetKind::Wrap)
+                } else {
+                    // whatever was counted since the reset is all we can know about
+                    self.continuous_wh += raw_wh as f64;
+                    Some(EnergyResetKind::Reset)
+                }
+            }
+        };
+        self.last_raw_wh = Some(raw_wh);
+        if detected.is_some() {
+            self.resets += 1;
+        }
+        if detected.is_some() || self.last_save.map_or(true, |t| t.elapsed().as_secs() >= SAVE_INTERVAL_SECS) {
+            self.save();
+        }
+        detected
+    }
+
+    // the running total since the last requested reset
+    pub fn total_wh(&self) -> f64 {
+        self.continuous_wh - self.reset_offset_wh
+    }
+
+    // restarts the running total from zero and returns what it was
+    pub fn clear(&mut self) -> f64 {
+        let previous = self.total_wh();
+        self.reset_offset_wh = self.continuous_wh;
+        self.resets += 1;
+        self.save();
+        previous
+    }
+
+    pub fn save(&mut self) {
+        self.last_save = Some(Instant::now());
+        save_json(&self.path, &self);
+    }
+}
+
+pub fn continuous_energy_discovery(serial: &str, unit_name: &str, state_topic: String, device_info: &DeviceInfo) -> (String, HAConfigPayload) {
+    let name = format!("{unit_name}-{CONTINUOUS_ENERGY_METRIC}");
+    let config_payload = HAConfigPayload {
+        name: name.clone(),
+        unique_id: name,
+        device: device_info.clone(),
+        state_topic,
+        device_class: Some("energy".to_string()),
+        state_class: Some("total_increasing".to_string()),
+        native_uom: Some("Wh".to_string()),
+        suggested_display_precision: Some(0),
+        value_template: Some("{{ value_json.value }}".to_string()),
+        ..Default::default()
+    };
+    (config_topic(serial, CONTINUOUS_ENERGY_METRIC), config_payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WRAP_WH: f32 = 10_000.0;
+
+    fn tracker(name: &str) -> EnergyTracker {
+        let dir = std::env::temp_dir().join(format!("pzem016mqtt-test-{}", std::process::id()));
+        EnergyTracker::load(&dir.to_string_lossy(), name)
+    }
+
+    #[tokio::test]
+    async fn drop_near_the_top_of_the_register_is_a_wrap() {
+        let mut t = tracker("wrap");
+        assert_eq!(t.update(9_950.0, WRAP_WH), None);
+        assert_eq!(t.update(30.0, WRAP_WH), Some(EnergyResetKind::Wrap));
+        assert_eq!(t.continuous_wh, 10_030.0);
+        assert_eq!(t.resets, 1);
+    }
+
+    #[tokio::test]
+    async fn drop_elsewhere_is_a_reset() {
+        let mut t = tracker("reset");
+        t.update(5_000.0, WRAP_WH);
+        assert_eq!(t.update(20.0, WRAP_WH), Some(EnergyResetKind::Reset));
+        assert_eq!(t.continuous_wh, 5_020.0);
+        assert_eq!(t.update(25.0, WRAP_WH), None);
+        assert_eq!(t.continuous_wh, 5_025.0);
+    }
+
+    #[tokio::test]
+    async fn clear_keeps_the_continuous_counter() {
+        let mut t = tracker("clear");
+        t.update(1_000.0, WRAP_WH);
+        assert_eq!(t.clear(), 1_000.0);
+        t.update(1_200.0, WRAP_WH);
+        assert_eq!(t.total_wh(), 200.0);
+        assert_eq!(t.continuous_wh, 1_200.0);
+    }
+}
@@ -5,4 +5,14 @@ pub enum MQTTError {
     Default(String),
     #[error("Received request for thread exit")]
     ExitingThread
-}
\ No newline at end of file
+}
+
+#[derive(Error,Clone,Debug)]
+pub enum ConfigError {
+    #[error("Can't read config file: {0}")]
+    Read(String),
+    #[error("Couldn't deserialize AppConfig: {0}")]
+    Parse(String),
+    #[error("Invalid config: {0}")]
+    Invalid(String),
+}
@@ -0,0 +1,86 @@
+use crate::config::AppConfig;
+use crate::ipc::IPCMessage;
+use crate::payload::{bridge_identifier, publish, Payload};
+use crate::timestamp::Timestamp;
+use pzem016lib::errors::PZEMError;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EnergyResetKind {
+    // the register passed its maximum and started again from zero
+    Wrap,
+    // cleared from the front panel or over modbus
+    Reset,
+    // the bridge's running total was restarted by a command; the meter's register is untouched
+    Requested,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum BridgeEvent {
+    EnergyReset {
+        meter: String,
+        breaker: String,
+        kind: EnergyResetKind,
+        previous_wh: f32,
+        current_wh: f32,
+    },
+    RuleTriggered {
+        rule: String,
+        meter: String,
+        breaker: String,
+        metric: String,
+        value: f32,
+        condition: String,
+    },
+    RuleCleared {
+        rule: String,
+        meter: String,
+        breaker: String,
+        metric: String,
+        value: f32,
+        condition: String,
+    },
+    // the meter stopped answering after several consecutive failed reads
+    MeterOffline {
+        meter: String,
+        breaker: String,
+        failed_reads: u32,
+    },
+    MeterOnline {
+        meter: String,
+        breaker: String,
+    },
+    CycleComplete {
+        detector: String,
+        meter: String,
+        breaker: String,
+        duration_secs: u64,
+        energy_wh: f64,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EventPayload {
+    #[serde(flatten)]
+    pub event: BridgeEvent,
+    pub timestamp: Timestamp,
+}
+
+pub fn events_topic(config: &AppConfig) -> String {
+    format!("pzem016mqtt/{}/events", bridge_identifier(config))
+}
+
+pub async fn publish_event(
+    event: BridgeEvent,
+    config: &AppConfig,
+    tx: &mpsc::Sender<IPCMessage>,
+) -> Result<(), PZEMError> {
+    let payload = EventPayload {
+        event,
+        timestamp: config.timestamp_format().now(),
+    };
+    publish(tx, events_topic(config), Payload::Event(payload), false).await
+}
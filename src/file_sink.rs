@@ -0,0 +1,360 @@
+use crate::config::{FileFormat, FileSinkSettings};
+use crate::consts::SINK_QUEUE_SIZE;
+use crate::readings::{MeterReading, MeterSample};
+use crate::sinks::{Sink, SinkMessage};
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::future::BoxFuture;
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+const TRIM_INTERVAL_SECS: u64 = 60;
+const CSV_HEADER: &str = "timestamp,meter,breaker,volts,amps,watts,watt_hours,frequency,power_factor,energy_wh\n";
+
+#[derive(Serialize)]
+struct FileRecord<'a> {
+    timestamp: DateTime<Utc>,
+    meter: String,
+    breaker: &'a str,
+    #[serde(flatten)]
+    reading: MeterReading,
+    energy_wh: f64,
+}
+
+pub fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn format_row(s: &MeterSample, format: FileFormat) -> Result<String, serde_json::Error> {
+    let r = &s.reading;
+    match format {
+        FileFormat::Csv => Ok(format!(
+            "{},{},{},{},{},{},{},{},{},{}\n",
+            s.at.to_rfc3339(),
+            csv_field(&s.device.key()),
+            csv_field(&s.device.breaker),
+            r.volts,
+            r.amps,
+            r.watts,
+            r.watt_hours,
+            r.frequency,
+            r.power_factor,
+            s.energy_wh
+        )),
+        FileFormat::Ndjson => {
+            let record = FileRecord {
+                timestamp: s.at,
+                meter: s.device.key(),
+                breaker: &s.device.breaker,
+                reading: s.reading,
+                energy_wh: s.energy_wh,
+            };
+            serde_json::to_string(&record).map(|l| l + "\n")
+        }
+    }
+}
+
+// replaces `path` with path.gz. If that day was already compressed, e.g. a late reading after a
+// restart reopened it, this part is appended as another gzip member, which gunzip reads as one
+fn gzip_file(path: &Path) -> io::Result<()> {
+    let gz_path = PathBuf::from(format!("{}.gz", path.display()));
+    let tmp_path = PathBuf::from(format!("{}.gz.tmp", path.display()));
+    let mut input = fs::File::open(path)?;
+    let mut encoder = GzEncoder::new(fs::File::create(&tmp_path)?, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+    if gz_path.exists() {
+        let mut existing = fs::OpenOptions::new().append(true).open(&gz_path)?;
+        io::copy(&mut fs::File::open(&tmp_path)?, &mut existing)?;
+        existing.sync_all()?;
+        fs::remove_file(&tmp_path)?;
+    } else {
+        fs::rename(&tmp_path, &gz_path)?;
+    }
+    fs::remove_file(path)
+}
+
+struct OpenDay {
+    day: NaiveDate,
+    // a day is split into numbered parts when max_total_mb is set, so a busy day can be trimmed too
+    part: u32,
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+pub struct FileSink {
+    settings: FileSinkSettings,
+    tz: Tz,
+}
+
+impl FileSink {
+    pub fn new(settings: &FileSinkSettings, tz: Tz) -> Self {
+        FileSink { settings: settings.clone(), tz }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self.settings.format() {
+            FileFormat::Csv => "csv",
+            FileFormat::Ndjson => "ndjson",
+        }
+    }
+
+    // {prefix}-{day}.{ext} for the first part of a day, {prefix}-{day}.{part}.{ext} after that
+    fn path_for(&self, day: NaiveDate, part: u32) -> PathBuf {
+        let name = match part {
+            0 => format!("{}-{day}.{}", self.settings.prefix(), self.extension()),
+            _ => format!("{}-{day}.{part}.{}", self.settings.prefix(), self.extension()),
+        };
+        Path::new(&self.settings.directory).join(name)
+    }
+
+    // the day and part of one of this sink's files, compressed or not
+    fn parse_name(&self, path: &Path) -> Option<(NaiveDate, u32)> {
+        let name = path.file_name()?.to_str()?;
+        let name = name.strip_suffix(".gz").unwrap_or(name);
+        let rest = name.strip_prefix(self.settings.prefix())?.strip_prefix('-')?;
+        let rest = rest.strip_suffix(self.extension())?.strip_suffix('.')?;
+        let (day, part) = match rest.split_once('.') {
+            Some((day, part)) => (day, part.parse().ok()?),
+            None => (rest, 0),
+        };
+        Some((NaiveDate::parse_from_str(day, "%Y-%m-%d").ok()?, part))
+    }
+
+    // this sink's files in the directory, oldest first
+    fn files(&self) -> Vec<(PathBuf, u64)> {
+        let mut files: Vec<((NaiveDate, u32), PathBuf, u64)> = match fs::read_dir(&self.settings.directory) {
+            Ok(entries) => entries
+                .filter_map(|e| e.ok())
+                .filter_map(|e| Some((self.parse_name(&e.path())?, e.path(), e.metadata().ok()?.len())))
+                .collect(),
+            Err(e) => {
+                warn!("couldn't list {}: {e}", self.settings.directory);
+                vec![]
+            }
+        };
+        files.sort();
+        files.into_iter().map(|(_, path, size)| (path, size)).collect()
+    }
+
+    // parts are kept to a quarter of the cap, so trimming never takes more than that at once
+    fn part_limit(&self) -> Option<u64> {
+        self.settings.max_total_mb.map(|mb| mb * 1024 * 1024 / 4)
+    }
+
+    // finishes off a day that is no longer being written: compress it, then trim the directory
+    fn close_day(&self, path: &Path, current: Option<&Path>) {
+        if self.settings.compress() && path.exists() {
+            if let Err(e) = gzip_file(path) {
+                error!("couldn't compress {}: {e}", path.display());
+            }
+        }
+        self.trim(current);
+    }
+
+    // deletes the oldest files until the directory is under max_total_mb
+    fn trim(&self, current: Option<&Path>) {
+        let max_bytes = match self.settings.max_total_mb {
+            Some(mb) => mb * 1024 * 1024,
+            None => return,
+        };
+        let files = self.files();
+        let mut total: u64 = files.iter().map(|(_, size)| size).sum();
+        for (old, size) in files {
+            if total <= max_bytes {
+                break;
+            }
+            if Some(old.as_path()) == current {
+                continue;
+            }
+            info!("removing {} to stay under {}MB", old.display(), max_bytes / 1024 / 1024);
+            match fs::remove_file(&old) {
+                Ok(_) => total -= size,
+                Err(e) => error!("couldn't remove {}: {e}", old.display()),
+            }
+        }
+    }
+
+    // carries on with the day's last part unless it has been compressed already
+    fn current_part(&self, day: NaiveDate) -> u32 {
+        let last = self
+            .files()
+            .iter()
+            .filter_map(|(path, _)| self.parse_name(path))
+            .filter(|(d, _)| *d == day)
+            .map(|(_, part)| part)
+            .max();
+        match last {
+            Some(part) if self.path_for(day, part).exists() => part,
+            Some(part) => part + 1,
+            None => 0,
+        }
+    }
+
+    async fn open_day(&self, day: NaiveDate, part: u32) -> io::Result<OpenDay> {
+        let path = self.path_for(day, part);
+        let mut file = OpenOptions::new().create(true).append(true).open(&path).await?;
+        let mut size = file.metadata().await?.len();
+        if self.settings.format() == FileFormat::Csv && size == 0 {
+            file.write_all(CSV_HEADER.as_bytes()).await?;
+            size = CSV_HEADER.len() as u64;
+        }
+        Ok(OpenDay {
+            day,
+            part,
+            path,
+            file,
+            size,
+        })
+    }
+}
+
+impl Sink for FileSink {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn queue_size(&self) -> usize {
+        self.settings.queue_size.unwrap_or(SINK_QUEUE_SIZE)
+    }
+
+    fn run(self: Box<Self>, mut rx: mpsc::Receiver<SinkMessage>) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            if let Err(e) = tokio::fs::create_dir_all(&self.settings.directory).await {
+                error!("Couldn't create {}, file sink is disabled: {e}", self.settings.directory);
+                return;
+            }
+            let sink: Arc<FileSink> = Arc::from(self);
+            // days left uncompressed by an earlier run
+            let today = Utc::now().with_timezone(&sink.tz).date_naive();
+            for (path, _) in sink.files() {
+                let earlier = sink.parse_name(&path).is_some_and(|(day, _)| day < today);
+                if earlier && path.extension().and_then(|e| e.to_str()) == Some(sink.extension()) {
+                    let sink = sink.clone();
+                    let _ = tokio::task::spawn_blocking(move || sink.close_day(&path, None)).await;
+                }
+            }
+
+            let mut open: Option<OpenDay> = None;
+            let mut last_trim = Instant::now();
+            while let Some(msg) = rx.recv().await {
+                let s = match msg {
+                    SinkMessage::Sample(s) => s,
+                    SinkMessage::ConfigReloaded | SinkMessage::Event(_) => continue,
+                };
+                // pollers stamp their readings before queueing them, so a reading from just
+                // before midnight can arrive after the day has turned; it goes in the open file
+                // rather than reopening a day that has been closed
+                let day = s.at.with_timezone(&sink.tz).date_naive();
+                let next = match &open {
+                    None => Some((day, sink.current_part(day))),
+                    Some(o) if day > o.day => Some((day, 0)),
+                    Some(o) if sink.part_limit().is_some_and(|limit| o.size >= limit) => Some((o.day, o.part + 1)),
+                    Some(_) => None,
+                };
+                if let Some((day, part)) = next {
+                    let previous = open.take();
+                    open = match sink.open_day(day, part).await {
+                        Ok(o) => Some(o),
+                        Err(e) => {
+                            error!("couldn't open {}: {e}", sink.path_for(day, part).display());
+                            None
+                        }
+                    };
+                    if let Some(mut previous) = previous {
+                        if let Err(e) = previous.file.flush().await {
+                            error!("couldn't flush {}: {e}", previous.path.display());
+                        }
+                        drop(previous.file);
+                        let current = open.as_ref().map(|o| o.path.clone());
+                        let sink = sink.clone();
+                        let _ = tokio::task::spawn_blocking(move || sink.close_day(&previous.path, current.as_deref())).await;
+                    }
+                }
+                let o = match open.as_mut() {
+                    Some(o) => o,
+                    None => continue,
+                };
+                match format_row(&s, sink.settings.format()) {
+                    Ok(row) => match o.file.write_all(row.as_bytes()).await {
+                        Ok(_) => o.size += row.len() as u64,
+                        Err(e) => error!("couldn't write reading to {}: {e}", o.path.display()),
+                    },
+                    Err(e) => error!("couldn't serialize reading for {}: {e}", s.device.key()),
+                }
+                // the cap applies while a day is being written too, not just when it closes
+                if sink.settings.max_total_mb.is_some() && last_trim.elapsed().as_secs() >= TRIM_INTERVAL_SECS {
+                    last_trim = Instant::now();
+                    let _ = o.file.flush().await;
+                    let current = o.path.clone();
+                    let trim_sink = sink.clone();
+                    let _ = tokio::task::spawn_blocking(move || trim_sink.trim(Some(&current))).await;
+                }
+            }
+            if let Some(mut o) = open {
+                if let Err(e) = o.file.flush().await {
+                    error!("couldn't flush {}: {e}", o.path.display());
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sink(format: FileFormat) -> FileSink {
+        let settings = FileSinkSettings {
+            directory: "/var/log/pzem".to_string(),
+            format: Some(format),
+            ..Default::default()
+        };
+        FileSink::new(&settings, chrono_tz::UTC)
+    }
+
+    fn day() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 1, 31).unwrap()
+    }
+
+    #[tokio::test]
+    async fn csv_fields_are_quoted_only_when_needed() {
+        assert_eq!(csv_field("kitchen"), "kitchen");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+    }
+
+    #[tokio::test]
+    async fn part_names_round_trip() {
+        let s = sink(FileFormat::Csv);
+        assert_eq!(s.path_for(day(), 0), Path::new("/var/log/pzem/pzem016-2024-01-31.csv"));
+        assert_eq!(s.path_for(day(), 2), Path::new("/var/log/pzem/pzem016-2024-01-31.2.csv"));
+        for part in [0, 1, 12] {
+            assert_eq!(s.parse_name(&s.path_for(day(), part)), Some((day(), part)));
+        }
+    }
+
+    #[tokio::test]
+    async fn compressed_files_are_recognised_and_others_ignored() {
+        let s = sink(FileFormat::Ndjson);
+        assert_eq!(s.parse_name(Path::new("pzem016-2024-01-31.3.ndjson.gz")), Some((day(), 3)));
+        assert_eq!(s.parse_name(Path::new("pzem016-2024-01-31.csv")), None);
+        assert_eq!(s.parse_name(Path::new("other-2024-01-31.ndjson")), None);
+        assert_eq!(s.parse_name(Path::new("pzem016-2024-01-31.x.ndjson")), None);
+    }
+}
@@ -0,0 +1,189 @@
+use crate::config::{Graphite, GraphiteProtocol};
+use crate::consts::SINK_QUEUE_SIZE;
+use crate::readings::MeterSample;
+use crate::sinks::{Sink, SinkMessage};
+use futures::future::BoxFuture;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::time::interval;
+
+// keep udp datagrams under a typical path mtu
+const UDP_MAX_PAYLOAD: usize = 1400;
+
+const METRICS: [(&str, fn(&MeterSample) -> f64); 7] = [
+    ("volts", |s| s.reading.volts as f64),
+    ("amps", |s| s.reading.amps as f64),
+    ("watts", |s| s.reading.watts as f64),
+    ("watt_hours", |s| s.reading.watt_hours as f64),
+    ("frequency", |s| s.reading.frequency as f64),
+    ("power_factor", |s| s.reading.power_factor as f64),
+    ("energy_wh", |s| s.energy_wh),
+];
+
+// dots separate path components and whitespace ends the path, so neither may appear in a node
+pub fn path_node(s: &str) -> String {
+    s.chars()
+        .map(|c| if c == '.' || c == ':' || c == '|' || c.is_whitespace() { '_' } else { c })
+        .collect()
+}
+
+fn metric_lines(s: &MeterSample, settings: &Graphite) -> Vec<String> {
+    let base = format!("{}.{}", settings.prefix(), path_node(&s.device.breaker));
+    METRICS
+        .iter()
+        .map(|(metric, value)| match settings.protocol() {
+            GraphiteProtocol::Graphite => format!("{base}.{metric} {} {}", value(s), s.at.timestamp()),
+            GraphiteProtocol::Statsd => format!("{base}.{metric}:{}|g", value(s)),
+        })
+        .collect()
+}
+
+struct GraphiteWriter {
+    settings: Graphite,
+    tcp: Option<TcpStream>,
+    udp: Option<UdpSocket>,
+}
+
+impl GraphiteWriter {
+    fn new(settings: &Graphite) -> Self {
+        GraphiteWriter {
+            settings: settings.clone(),
+            tcp: None,
+            udp: None,
+        }
+    }
+
+    async fn write(&mut self, lines: &[String]) {
+        let result = match self.settings.protocol() {
+            GraphiteProtocol::Graphite => self.write_tcp(lines).await,
+            GraphiteProtocol::Statsd => self.write_udp(lines).await,
+        };
+        if let Err(e) = result {
+            warn!("dropped {} graphite metrics sending to {}: {e}", lines.len(), self.settings.addr);
+        }
+    }
+
+    // connects on first use and again after any failure
+    async fn write_tcp(&mut self, lines: &[String]) -> std::io::Result<()> {
+        if self.tcp.is_none() {
+            self.tcp = Some(TcpStream::connect(&self.settings.addr).await?);
+        }
+        let body = lines.join("\n") + "\n";
+        let result = match self.tcp.as_mut() {
+            Some(stream) => stream.write_all(body.as_bytes()).await,
+            None => Ok(()),
+        };
+        if result.is_err() {
+            self.tcp = None;
+        }
+        result
+    }
+
+    async fn write_udp(&mut self, lines: &[String]) -> std::io::Result<()> {
+        if self.udp.is_none() {
+            let sock = UdpSocket::bind("0.0.0.0:0").await?;
+            sock.connect(&self.settings.addr).await?;
+            self.udp = Some(sock);
+        }
+        let sock = match &self.udp {
+            Some(sock) => sock,
+            None => return Ok(()),
+        };
+        // statsd takes newline separated metrics, so pack as many as fit into each datagram
+        let mut packet = String::new();
+        for line in lines {
+            if !packet.is_empty() && packet.len() + line.len() + 1 > UDP_MAX_PAYLOAD {
+                sock.send(packet.as_bytes()).await?;
+                packet.clear();
+            }
+            if !packet.is_empty() {
+                packet.push('\n');
+            }
+            packet.push_str(line);
+        }
+        if !packet.is_empty() {
+            sock.send(packet.as_bytes()).await?;
+        }
+        Ok(())
+    }
+}
+
+pub struct GraphiteSink {
+    settings: Graphite,
+}
+
+impl GraphiteSink {
+    pub fn new(settings: &Graphite) -> Self {
+        GraphiteSink { settings: settings.clone() }
+    }
+}
+
+impl Sink for GraphiteSink {
+    fn name(&self) -> &'static str {
+        "graphite"
+    }
+
+    fn queue_size(&self) -> usize {
+        self.settings.queue_size.unwrap_or(SINK_QUEUE_SIZE)
+    }
+
+    fn run(self: Box<Self>, rx: mpsc::Receiver<SinkMessage>) -> BoxFuture<'static, ()> {
+        Box::pin(graphite_loop(rx, self.settings))
+    }
+}
+
+// only the latest reading of each meter within a flush interval is sent
+async fn graphite_loop(mut rx: mpsc::Receiver<SinkMessage>, settings: Graphite) {
+    let mut writer = GraphiteWriter::new(&settings);
+    let mut pending: BTreeMap<String, Arc<MeterSample>> = BTreeMap::new();
+    let mut flush = interval(Duration::from_secs(settings.flush_interval_secs()));
+    loop {
+        select! {
+            r = rx.recv() => match r {
+                Some(SinkMessage::Sample(s)) => {
+                    pending.insert(s.device.key(), s);
+                    continue;
+                }
+                Some(SinkMessage::ConfigReloaded | SinkMessage::Event(_)) => continue,
+                None => {
+                    if !pending.is_empty() {
+                        let lines: Vec<String> = pending.values().flat_map(|s| metric_lines(s, &settings)).collect();
+                        writer.write(&lines).await;
+                    }
+                    debug!("graphite channel closed, stopping graphite task");
+                    return;
+                }
+            },
+            _ = flush.tick() => {
+                if pending.is_empty() {
+                    continue;
+                }
+            }
+        }
+        let lines: Vec<String> = pending.values().flat_map(|s| metric_lines(s, &settings)).collect();
+        pending.clear();
+        writer.write(&lines).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn plain_names_are_unchanged() {
+        assert_eq!(path_node("kitchen_outlets"), "kitchen_outlets");
+        assert_eq!(path_node("garage-2"), "garage-2");
+    }
+
+    #[tokio::test]
+    async fn separators_and_whitespace_are_replaced() {
+        assert_eq!(path_node("hall.lights"), "hall_lights");
+        assert_eq!(path_node("pump:1|a"), "pump_1_a");
+        assert_eq!(path_node("living room\tlamp\n"), "living_room_lamp_");
+    }
+}
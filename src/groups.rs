@@ -0,0 +1,414 @@
+use crate::config::{AppConfig, MeterGroup, RemainderMeter, Tariffs};
+use crate::consts::POLL_TIME;
+use crate::ipc::IPCMessage;
+use crate::payload::{bridge_identifier, json_metric_configs, json_metric_topics, publish, AggregateStatePayload, DeviceInfo, EntityCategory, HAConfigPayload, Payload};
+use crate::tariffs::{cost_discovery_payloads, cost_discovery_topics, CostPayload, TariffSchedule};
+use crate::readings::{CachedReading, LATEST_READINGS};
+use crate::state::{load_json, save_json};
+use crate::timestamp::TimestampFormat;
+use crate::{SETTINGS, SHUTDOWN};
+use chrono::Utc;
+use pzem016lib::errors::PZEMError;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+
+const GROUP_FIELDS: [&str; 5] = ["volts", "amps", "watts", "watt_hours", "frequency"];
+// currents on different circuits aren't in phase, so they can't simply be subtracted
+const REMAINDER_FIELDS: [&str; 4] = ["volts", "watts", "watt_hours", "frequency"];
+const STALE_AVAILABILITY_TEMPLATE: &str = "{{ 'offline' if value_json.stale else 'online' }}";
+const SAVE_INTERVAL_SECS: u64 = 60;
+// meters are polled at slightly different times, so the balance wobbles around zero when
+// there's no unmetered load
+const CT_ERROR_TOLERANCE_WH: f64 = 10.0;
+
+pub fn slug(name: &str) -> String {
+    name.to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+fn group_serial(group: &MeterGroup) -> String {
+    format!("group-{}", slug(&group.name))
+}
+
+fn group_state_topic(group: &MeterGroup) -> String {
+    format!("pzem016mqtt/{}/state", group_serial(group))
+}
+
+fn group_cost_topic(group: &MeterGroup) -> String {
+    format!("pzem016mqtt/{}/cost", group_serial(group))
+}
+
+pub fn group_discovery_topics(group: &MeterGroup, config: &AppConfig) -> Vec<String> {
+    let mut topics = json_metric_topics(&group_serial(group), &GROUP_FIELDS);
+    if config.tariffs.is_some() {
+        topics.extend(cost_discovery_topics(&group_serial(group)));
+    }
+    topics
+}
+
+pub fn virtual_device_info(serial: &str, name: &str, config: &AppConfig) -> DeviceInfo {
+    DeviceInfo {
+        identifiers: vec![format!("{}-{serial}", bridge_identifier(config))],
+        manufacturer: "pzem016mqtt".to_string(),
+        name: name.to_string(),
+        model: "virtual meter".to_string(),
+        via_device: Some(bridge_identifier(config)),
+        ..Default::default()
+    }
+}
+
+async fn publish_group_discovery(group: &MeterGroup, config: &AppConfig, tx: &mpsc::Sender<IPCMessage>) -> Result<(), PZEMError> {
+    let serial = group_serial(group);
+    let state_topic = group_state_topic(group);
+    let device_info = virtual_device_info(&serial, &group.name, config);
+    for (topic, mut config_payload) in json_metric_configs(&serial, &format!("pzem016-{serial}"), &GROUP_FIELDS, &state_topic, &device_info) {
+        config_payload.availability_topic = Some(state_topic.clone());
+        config_payload.availability_template = Some(STALE_AVAILABILITY_TEMPLATE.to_string());
+        publish(tx, topic, Payload::Config(config_payload), true).await?;
+    }
+    if let Some(tariffs) = &config.tariffs {
+        for (topic, config_payload) in cost_discovery_payloads(&serial, &format!("pzem016-{serial}"), &group_cost_topic(group), &tariffs.currency, &device_info) {
+            publish(tx, topic, Payload::Config(config_payload), true).await?;
+        }
+    }
+    Ok(())
+}
+
+// members' costs summed, plus any fixed charge billed to the group itself
+pub fn group_cost(
+    group: &MeterGroup,
+    config: &AppConfig,
+    latest: &HashMap<String, CachedReading>,
+    schedule: &TariffSchedule,
+) -> CostPayload {
+    let members: Vec<&CachedReading> = group
+        .members
+        .iter()
+        .filter_map(|breaker| config.device_by_breaker(breaker).ok())
+        .filter_map(|device| latest.get(&device.key()))
+        .collect();
+    let now = Utc::now();
+    let (fixed_today, fixed_month) = schedule.fixed_charges(&group.name, now);
+    let today = members.iter().filter_map(|c| c.cost_today).sum::<f64>() + fixed_today;
+    let month = members.iter().filter_map(|c| c.cost_month).sum::<f64>() + fixed_month;
+    let watts: f32 = members.iter().map(|c| c.reading.watts).sum();
+    schedule.payload(today, month, watts, now)
+}
+
+pub fn aggregate(
+    group: &MeterGroup,
+    config: &AppConfig,
+    latest: &HashMap<String, CachedReading>,
+    format: TimestampFormat,
+) -> AggregateStatePayload {
+    let members: Vec<&CachedReading> = group
+        .members
+        .iter()
+        .filter_map(|breaker| config.device_by_breaker(breaker).ok())
+        .filter_map(|device| latest.get(&device.key()))
+        .collect();
+    let fresh: Vec<&CachedReading> = members
+        .iter()
+        .copied()
+        .filter(|c| c.age_secs() <= group.stale_after())
+        .collect();
+    let all_reported = members.len() == group.members.len();
+    // averages come from fresh readings where possible, but fall back to whatever we have
+    let averaged = if fresh.is_empty() { &members } else { &fresh };
+    let mean = |f: fn(&CachedReading) -> f32| -> Option<f32> {
+        if averaged.is_empty() {
+            None
+        } else {
+            Some(averaged.iter().map(|c| f(c)).sum::<f32>() / averaged.len() as f32)
+        }
+    };
+    AggregateStatePayload {
+        volts: mean(|c| c.reading.volts),
+        amps: Some(members.iter().map(|c| c.reading.amps).sum()),
+        watts: members.iter().map(|c| c.reading.watts).sum(),
+        // a partial energy sum would look like a counter reset to HA
+        watt_hours: if all_reported { Some(members.iter().map(|c| c.energy_wh as f32).sum()) } else { None },
+        frequency: mean(|c| c.reading.frequency),
+        members: group.members.len(),
+        members_reporting: fresh.len(),
+        stale: fresh.len() < group.members.len(),
+        ct_error: None,
+        unclamped_watts: None,
+        last_seen: format.now(),
+    }
+}
+
+fn remainder_serial(remainder: &RemainderMeter) -> String {
+    format!("remainder-{}", slug(&remainder.name))
+}
+
+fn remainder_state_topic(remainder: &RemainderMeter) -> String {
+    format!("pzem016mqtt/{}/state", remainder_serial(remainder))
+}
+
+fn ct_error_config_topic(serial: &str) -> String {
+    format!("homeassistant/binary_sensor/pzem016-{serial}/ct_error/config")
+}
+
+pub fn remainder_discovery_topics(remainder: &RemainderMeter) -> Vec<String> {
+    let serial = remainder_serial(remainder);
+    let mut topics = json_metric_topics(&serial, &REMAINDER_FIELDS);
+    topics.push(ct_error_config_topic(&serial));
+    topics
+}
+
+async fn publish_remainder_discovery(remainder: &RemainderMeter, config: &AppConfig, tx: &mpsc::Sender<IPCMessage>) -> Result<(), PZEMError> {
+    let serial = remainder_serial(remainder);
+    let unit_name = format!("pzem016-{serial}");
+    let state_topic = remainder_state_topic(remainder);
+    let device_info = virtual_device_info(&serial, &remainder.name, config);
+    for (topic, mut config_payload) in json_metric_configs(&serial, &unit_name, &REMAINDER_FIELDS, &state_topic, &device_info) {
+        config_payload.availability_topic = Some(state_topic.clone());
+        config_payload.availability_template = Some(STALE_AVAILABILITY_TEMPLATE.to_string());
+        publish(tx, topic, Payload::Config(config_payload), true).await?;
+    }
+    let name = format!("{unit_name}-ct_error");
+    let ct_error = HAConfigPayload {
+        name: name.clone(),
+        unique_id: name,
+        device: device_info,
+        state_topic,
+        device_class: Some("problem".to_string()),
+        entity_category: Some(EntityCategory::Diagnostic),
+        value_template: Some("{{ 'ON' if value_json.ct_error else 'OFF' }}".to_string()),
+        ..Default::default()
+    };
+    publish(tx, ct_error_config_topic(&serial), Payload::Config(ct_error), true).await
+}
+
+// The remainder's own energy counter. Each poll adds what the mains counted since the previous
+// one less what the branches counted, so meters installed or reset at different times don't
+// throw it off the way subtracting their lifetime totals would.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RemainderEnergy {
+    // every member's continuous counter at the previous poll, by PZEMDevice::key()
+    last_wh: HashMap<String, f64>,
+    // negative while the branches have counted more than the mains
+    balance_wh: f64,
+    // the highest the balance has been; published so the counter never goes down
+    published_wh: f64,
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    last_save: Option<Instant>,
+}
+
+impl RemainderEnergy {
+    pub fn load(state_dir: &str, name: &str) -> Self {
+        let path = Path::new(state_dir).join("remainders").join(format!("{}.json", slug(name)));
+        let mut energy = load_json::<RemainderEnergy>(&path).unwrap_or_default();
+        energy.path = path;
+        energy
+    }
+
+    // `branches` are (key, continuous Wh) of every branch
+    fn update(&mut self, mains: (String, f64), branches: Vec<(String, f64)>) {
+        let mut baseline = self.last_wh.get(&mains.0).map_or(true, |last| mains.1 < *last);
+        let mut branch_wh = 0.0;
+        for (key, wh) in branches.iter() {
+            match self.last_wh.get(key) {
+                Some(last) if wh >= last => branch_wh += wh - last,
+                // a member we haven't seen, or whose counter went backwards: start over from here
+                _ => baseline = true,
+            }
+        }
+        if !baseline {
+            self.balance_wh += mains.1 - self.last_wh[&mains.0] - branch_wh;
+            self.published_wh = self.published_wh.max(self.balance_wh);
+        }
+        self.last_wh = std::iter::once(mains).chain(branches).collect();
+        if baseline || self.last_save.map_or(true, |t| t.elapsed().as_secs() >= SAVE_INTERVAL_SECS) {
+            self.last_save = Some(Instant::now());
+            save_json(&self.path, &self);
+        }
+    }
+}
+
+pub fn remainder(
+    remainder: &RemainderMeter,
+    config: &AppConfig,
+    latest: &HashMap<String, CachedReading>,
+    energy: &mut RemainderEnergy,
+    format: TimestampFormat,
+) -> AggregateStatePayload {
+    let lookup = |breaker: &str| -> Option<(String, &CachedReading)> {
+        let key = config.device_by_breaker(breaker).ok()?.key();
+        latest.get(&key).map(|c| (key, c))
+    };
+    let branch_names = remainder.branches(config);
+    let mains_keyed = lookup(&remainder.mains);
+    let branches_keyed: Vec<(String, &CachedReading)> = branch_names.iter().filter_map(|b| lookup(b)).collect();
+    let mains = mains_keyed.as_ref().map(|(_, c)| *c);
+    let branches: Vec<&CachedReading> = branches_keyed.iter().map(|(_, c)| *c).collect();
+    let fresh = |c: &CachedReading| c.age_secs() <= remainder.stale_after();
+    let members_reporting = mains.iter().copied().chain(branches.iter().copied()).filter(|c| fresh(c)).count();
+    let members = branch_names.len() + 1;
+    let all_reported = mains.is_some() && branches.len() == branch_names.len();
+
+    let branch_watts: f32 = branches.iter().map(|c| c.reading.watts).sum();
+    let unclamped_watts = mains.map(|m| m.reading.watts - branch_watts).unwrap_or(0.0);
+    if let (true, Some((key, m))) = (all_reported, &mains_keyed) {
+        energy.update((key.clone(), m.energy_wh), branches_keyed.iter().map(|(k, c)| (k.clone(), c.energy_wh)).collect());
+    }
+    // a branch reading more than the mains means a CT is reversed, on the wrong circuit or miscalibrated
+    let ct_error = all_reported && (unclamped_watts < 0.0 || energy.balance_wh < -CT_ERROR_TOLERANCE_WH);
+    if ct_error {
+        warn!("remainder {} is negative ({unclamped_watts} W), check the CTs", remainder.name);
+    }
+    AggregateStatePayload {
+        volts: mains.map(|m| m.reading.volts),
+        amps: None,
+        watts: unclamped_watts.max(0.0),
+        watt_hours: if all_reported { Some(energy.published_wh as f32) } else { None },
+        frequency: mains.map(|m| m.reading.frequency),
+        members,
+        members_reporting,
+        stale: members_reporting < members,
+        ct_error: Some(ct_error),
+        unclamped_watts: Some(unclamped_watts),
+        last_seen: format.now(),
+    }
+}
+
+pub async fn group_loop(tx: mpsc::Sender<IPCMessage>) -> Result<(), PZEMError> {
+    let mut announced: Vec<MeterGroup> = vec![];
+    let mut announced_remainders: Vec<RemainderMeter> = vec![];
+    let mut announced_tariffs: Option<Tariffs> = None;
+    let mut remainder_energy: HashMap<String, RemainderEnergy> = HashMap::new();
+    loop {
+        if SHUTDOWN.get().is_some() {
+            return Err(PZEMError::ExitingThread);
+        }
+        let config = SETTINGS.read().await.clone();
+        // tariffs add entities to every group, so a change means announcing them all again
+        if config.tariffs != announced_tariffs {
+            announced.clear();
+            announced_tariffs = config.tariffs.clone();
+        }
+        let tariffs = match config.tariffs.as_ref().map(|t| TariffSchedule::new(t, &config)) {
+            Some(Ok(t)) => Some(t),
+            Some(Err(e)) => {
+                error!("group cost tracking disabled: {e}");
+                None
+            }
+            None => None,
+        };
+        for group in config.groups().iter().filter(|g| !announced.contains(g)) {
+            publish_group_discovery(group, &config, &tx).await?;
+        }
+        announced = config.groups().to_vec();
+        for r in config.remainders().iter().filter(|r| !announced_remainders.contains(r)) {
+            publish_remainder_discovery(r, &config, &tx).await?;
+        }
+        announced_remainders = config.remainders().to_vec();
+
+        let latest = LATEST_READINGS.read().await.clone();
+        for group in config.groups() {
+            let state = aggregate(group, &config, &latest, config.timestamp_format());
+            if state.stale {
+                debug!("group {} is stale: {}/{} members reporting", group.name, state.members_reporting, state.members);
+            }
+            publish(&tx, group_state_topic(group), Payload::AggregateState(state), false).await?;
+            if let Some(schedule) = &tariffs {
+                let cost = group_cost(group, &config, &latest, schedule);
+                publish(&tx, group_cost_topic(group), Payload::Cost(cost), false).await?;
+            }
+        }
+        remainder_energy.retain(|name, _| config.remainders().iter().any(|r| &r.name == name));
+        for r in config.remainders() {
+            let energy = remainder_energy
+                .entry(r.name.clone())
+                .or_insert_with(|| RemainderEnergy::load(config.state_dir(), &r.name));
+            let state = remainder(r, &config, &latest, energy, config.timestamp_format());
+            publish(&tx, remainder_state_topic(r), Payload::AggregateState(state), false).await?;
+        }
+        let _ = sleep(Duration::from_secs(POLL_TIME as u64)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PZEMDevice;
+    use crate::readings::MeterReading;
+
+    fn config() -> AppConfig {
+        let device = |addr: u8, breaker: &str| PZEMDevice {
+            addr,
+            port: "/dev/ttyUSB0".to_string(),
+            breaker: breaker.to_string(),
+            ..Default::default()
+        };
+        AppConfig {
+            devices: Some(vec![device(1, "kitchen"), device(2, "garage")]),
+            ..Default::default()
+        }
+    }
+
+    fn group() -> MeterGroup {
+        MeterGroup {
+            name: "downstairs".to_string(),
+            members: vec!["kitchen".to_string(), "garage".to_string()],
+            stale_after: Some(60),
+        }
+    }
+
+    fn cached(volts: f32, watts: f32, energy_wh: f64, age_secs: u64) -> CachedReading {
+        CachedReading {
+            reading: MeterReading { volts, amps: watts / volts, watts, frequency: 50.0, ..Default::default() },
+            energy_wh,
+            cost_today: None,
+            cost_month: None,
+            at: Instant::now() - Duration::from_secs(age_secs),
+        }
+    }
+
+    #[tokio::test]
+    async fn sums_power_and_energy_and_averages_voltage() {
+        let latest = HashMap::from([
+            ("/dev/ttyUSB0/1".to_string(), cached(230.0, 460.0, 1000.0, 0)),
+            ("/dev/ttyUSB0/2".to_string(), cached(240.0, 240.0, 500.0, 0)),
+        ]);
+        let state = aggregate(&group(), &config(), &latest, TimestampFormat::default());
+        assert_eq!(state.watts, 700.0);
+        assert_eq!(state.amps, Some(3.0));
+        assert_eq!(state.watt_hours, Some(1500.0));
+        assert_eq!(state.volts, Some(235.0));
+        assert_eq!(state.members_reporting, 2);
+        assert!(!state.stale);
+    }
+
+    #[tokio::test]
+    async fn missing_member_leaves_energy_out() {
+        let latest = HashMap::from([("/dev/ttyUSB0/1".to_string(), cached(230.0, 460.0, 1000.0, 0))]);
+        let state = aggregate(&group(), &config(), &latest, TimestampFormat::default());
+        assert_eq!(state.watts, 460.0);
+        assert_eq!(state.watt_hours, None);
+        assert_eq!(state.members_reporting, 1);
+        assert!(state.stale);
+    }
+
+    #[tokio::test]
+    async fn stale_members_are_left_out_of_averages() {
+        let latest = HashMap::from([
+            ("/dev/ttyUSB0/1".to_string(), cached(230.0, 460.0, 1000.0, 0)),
+            ("/dev/ttyUSB0/2".to_string(), cached(200.0, 200.0, 500.0, 600)),
+        ]);
+        let state = aggregate(&group(), &config(), &latest, TimestampFormat::default());
+        assert_eq!(state.volts, Some(230.0));
+        assert_eq!(state.watt_hours, Some(1500.0));
+        assert_eq!(state.members_reporting, 1);
+        assert!(state.stale);
+    }
+}
@@ -0,0 +1,302 @@
+use crate::config::{AppConfig, History};
+use crate::consts::SINK_QUEUE_SIZE;
+use crate::readings::MeterSample;
+use crate::sinks::{Sink, SinkMessage};
+use futures::future::BoxFuture;
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::mpsc;
+
+const ROLLUP_INTERVAL_SECS: u64 = 60;
+const MINUTE_MILLIS: i64 = 60_000;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS readings (
+    meter TEXT NOT NULL,
+    ts INTEGER NOT NULL,
+    volts REAL NOT NULL,
+    amps REAL NOT NULL,
+    watts REAL NOT NULL,
+    watt_hours REAL NOT NULL,
+    frequency REAL NOT NULL,
+    power_factor REAL NOT NULL,
+    energy_wh REAL NOT NULL
+);
+CREATE INDEX IF NOT EXISTS readings_meter_ts ON readings (meter, ts);
+CREATE TABLE IF NOT EXISTS readings_1m (
+    meter TEXT NOT NULL,
+    ts INTEGER NOT NULL,
+    volts REAL NOT NULL,
+    amps REAL NOT NULL,
+    watts REAL NOT NULL,
+    watt_hours REAL NOT NULL,
+    frequency REAL NOT NULL,
+    power_factor REAL NOT NULL,
+    energy_wh REAL NOT NULL,
+    samples INTEGER NOT NULL,
+    PRIMARY KEY (meter, ts)
+);
+";
+
+// one row returned by a history query: a raw reading, or the average of a bucket of them
+#[derive(Debug, Clone)]
+pub struct HistoryRow {
+    pub ts_millis: i64,
+    pub volts: f64,
+    pub amps: f64,
+    pub watts: f64,
+    pub watt_hours: f64,
+    pub frequency: f64,
+    pub power_factor: f64,
+    pub energy_wh: f64,
+    pub samples: i64,
+}
+
+pub struct HistoryStore {
+    conn: Connection,
+    // start of the last minute rolled up for each meter, by PZEMDevice::key(); meters report
+    // at their own pace, so each has its own
+    rolled_up: HashMap<String, i64>,
+}
+
+impl HistoryStore {
+    pub fn open(path: &str) -> rusqlite::Result<Self> {
+        if let Some(parent) = Path::new(path).parent() {
+            let _ = std::fs::create_dir_all(parent);
+        }
+        let conn = Connection::open(path)?;
+        // WAL lets readers (the HTTP API) query while the history task writes
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.execute_batch(SCHEMA)?;
+        let mut store = HistoryStore {
+            conn,
+            rolled_up: HashMap::new(),
+        };
+        // meters with raw readings from an earlier run that may not have been rolled up yet
+        let meters: Vec<String> = store
+            .conn
+            .prepare("SELECT DISTINCT meter FROM readings")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        for meter in meters {
+            store.track(&meter)?;
+        }
+        Ok(store)
+    }
+
+    pub fn open_read_only(path: &str) -> rusqlite::Result<Self> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        Ok(HistoryStore {
+            conn,
+            rolled_up: HashMap::new(),
+        })
+    }
+
+    fn track(&mut self, meter: &str) -> rusqlite::Result<()> {
+        if !self.rolled_up.contains_key(meter) {
+            let last: i64 = self.conn.query_row(
+                "SELECT COALESCE(MAX(ts), 0) FROM readings_1m WHERE meter = ?1",
+                params![meter],
+                |row| row.get(0),
+            )?;
+            self.rolled_up.insert(meter.to_string(), last);
+        }
+        Ok(())
+    }
+
+    pub fn insert(&mut self, samples: &[Arc<MeterSample>]) -> rusqlite::Result<()> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO readings (meter, ts, volts, amps, watts, watt_hours, frequency, power_factor, energy_wh)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            )?;
+            for r in samples {
+                stmt.execute(params![
+                    r.device.key(),
+                    r.at.timestamp_millis(),
+                    r.reading.volts,
+                    r.reading.amps,
+                    r.reading.watts,
+                    r.reading.watt_hours,
+                    r.reading.frequency,
+                    r.reading.power_factor,
+                    r.energy_wh,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        for r in samples {
+            self.track(&r.device.key())?;
+        }
+        Ok(())
+    }
+
+    // averages every complete minute of raw readings into readings_1m; counters keep their
+    // last value rather than the mean. Each meter's last rolled-up minute is done again in
+    // case a reading for it arrived late.
+    pub fn rollup(&mut self, now: DateTime<Utc>) -> rusqlite::Result<usize> {
+        let until = now.timestamp_millis() / MINUTE_MILLIS * MINUTE_MILLIS;
+        let mut rows = 0;
+        for (meter, since) in self.rolled_up.iter_mut() {
+            rows += self.conn.execute(
+                "INSERT OR REPLACE INTO readings_1m
+                     (meter, ts, volts, amps, watts, watt_hours, frequency, power_factor, energy_wh, samples)
+                 SELECT meter, ts / ?1 * ?1, AVG(volts), AVG(amps), AVG(watts), MAX(watt_hours),
+                        AVG(frequency), AVG(power_factor), MAX(energy_wh), COUNT(*)
+                 FROM readings WHERE meter = ?2 AND ts >= ?3 AND ts < ?4
+                 GROUP BY ts / ?1",
+                params![MINUTE_MILLIS, meter, *since, until],
+            )?;
+            *since = self.conn.query_row(
+                "SELECT COALESCE(MAX(ts), ?2) FROM readings_1m WHERE meter = ?1",
+                params![meter, *since],
+                |row| row.get(0),
+            )?;
+        }
+        Ok(rows)
+    }
+
+    // readings for `meter` in [from, to); with a step of a minute or more they come from the
+    // 1-minute averages, which go back further than the raw readings
+    pub fn readings(&self, meter: &str, from: DateTime<Utc>, to: DateTime<Utc>, step_secs: Option<u64>) -> rusqlite::Result<Vec<HistoryRow>> {
+        let step_millis = step_secs.unwrap_or(0) as i64 * 1000;
+        let table = if step_millis >= MINUTE_MILLIS { "readings_1m" } else { "readings" };
+        let samples = if table == "readings_1m" { "samples" } else { "1" };
+        // without a step every reading is its own bucket
+        let sql = format!(
+            "SELECT CASE WHEN ?4 > 0 THEN ts / ?4 * ?4 ELSE ts END AS bucket, AVG(volts), AVG(amps), AVG(watts),
+                    MAX(watt_hours), AVG(frequency), AVG(power_factor), MAX(energy_wh), SUM({samples})
+             FROM {table} WHERE meter = ?1 AND ts >= ?2 AND ts < ?3
+             GROUP BY bucket ORDER BY bucket"
+        );
+        let mut stmt = self.conn.prepare(&sql)?;
+        let rows = stmt.query_map(params![meter, from.timestamp_millis(), to.timestamp_millis(), step_millis], |row| {
+            Ok(HistoryRow {
+                ts_millis: row.get(0)?,
+                volts: row.get(1)?,
+                amps: row.get(2)?,
+                watts: row.get(3)?,
+                watt_hours: row.get(4)?,
+                frequency: row.get(5)?,
+                power_factor: row.get(6)?,
+                energy_wh: row.get(7)?,
+                samples: row.get(8)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    // Wh used by `meter` in [from, to), from the continuous energy counter: its last value at or
+    // before `to` less its last value at or before `from`, so consecutive ranges add up to the
+    // whole. A meter first seen inside the range counts from its first reading; None without data.
+    pub fn energy_between(&self, meter: &str, from: DateTime<Utc>, to: DateTime<Utc>) -> rusqlite::Result<Option<f64>> {
+        let end = match self.counter_at(meter, to)? {
+            Some(wh) => wh,
+            None => return Ok(None),
+        };
+        let start = match self.counter_at(meter, from)? {
+            Some(wh) => wh,
+            None => self.conn.query_row(
+                "SELECT MIN(energy_wh) FROM (
+                     SELECT energy_wh FROM readings WHERE meter = ?1 AND ts >= ?2
+                     UNION ALL
+                     SELECT energy_wh FROM readings_1m WHERE meter = ?1 AND ts >= ?2
+                 )",
+                params![meter, from.timestamp_millis()],
+                |row| row.get::<_, Option<f64>>(0),
+            )?.unwrap_or(end),
+        };
+        Ok(Some((end - start).max(0.0)))
+    }
+
+    // the counter as it stood at `t`; a minute's rollup holds the counter at the end of that
+    // minute, so it only counts once the minute is over
+    fn counter_at(&self, meter: &str, t: DateTime<Utc>) -> rusqlite::Result<Option<f64>> {
+        self.conn
+            .query_row(
+                "SELECT energy_wh FROM (
+                     SELECT ts, energy_wh FROM readings WHERE meter = ?1 AND ts <= ?2
+                     UNION ALL
+                     SELECT ts + 60000, energy_wh FROM readings_1m WHERE meter = ?1 AND ts + 60000 <= ?2
+                 ) ORDER BY ts DESC LIMIT 1",
+                params![meter, t.timestamp_millis()],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    pub fn prune(&mut self, now: DateTime<Utc>, settings: &History) -> rusqlite::Result<()> {
+        let raw_cutoff = now - Duration::days(settings.raw_retention_days() as i64);
+        let rollup_cutoff = now - Duration::days(settings.rollup_retention_days() as i64);
+        self.conn.execute("DELETE FROM readings WHERE ts < ?1", params![raw_cutoff.timestamp_millis()])?;
+        self.conn.execute("DELETE FROM readings_1m WHERE ts < ?1", params![rollup_cutoff.timestamp_millis()])?;
+        Ok(())
+    }
+}
+
+pub struct HistorySink {
+    settings: History,
+    path: String,
+}
+
+impl HistorySink {
+    pub fn new(settings: &History, config: &AppConfig) -> Self {
+        HistorySink {
+            settings: settings.clone(),
+            path: settings.path(config),
+        }
+    }
+}
+
+impl Sink for HistorySink {
+    fn name(&self) -> &'static str {
+        "history"
+    }
+
+    fn queue_size(&self) -> usize {
+        self.settings.queue_size.unwrap_or(SINK_QUEUE_SIZE)
+    }
+
+    fn run(self: Box<Self>, rx: mpsc::Receiver<SinkMessage>) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            let _ = tokio::task::spawn_blocking(move || history_loop(rx, self.settings, self.path)).await;
+        })
+    }
+}
+
+// runs on a blocking thread so sqlite never holds up the async runtime
+fn history_loop(mut rx: mpsc::Receiver<SinkMessage>, settings: History, path: String) {
+    let mut store = match HistoryStore::open(&path) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Couldn't open history database {path}, history is disabled: {e}");
+            return;
+        }
+    };
+    info!("Recording reading history to {path}");
+    let mut last_rollup = Instant::now();
+    while let Some(first) = rx.blocking_recv() {
+        let mut batch: Vec<Arc<MeterSample>> = vec![];
+        for msg in std::iter::once(first).chain(std::iter::from_fn(|| rx.try_recv().ok())) {
+            if let SinkMessage::Sample(s) = msg {
+                batch.push(s);
+            }
+        }
+        if let Err(e) = store.insert(&batch) {
+            error!("Couldn't write {} readings to history: {e}", batch.len());
+        }
+        if last_rollup.elapsed().as_secs() >= ROLLUP_INTERVAL_SECS {
+            last_rollup = Instant::now();
+            let now = Utc::now();
+            if let Err(e) = store.rollup(now).and_then(|_| store.prune(now, &settings)) {
+                error!("History rollup failed: {e}");
+            }
+        }
+    }
+    debug!("history channel closed, stopping history task");
+}
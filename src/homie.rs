@@ -0,0 +1,256 @@
+use crate::config::{AppConfig, MqttOutput, PZEMDevice};
+use crate::consts::SINK_QUEUE_SIZE;
+use crate::energy_tracker::ENERGY_RESET_REQUESTS;
+use crate::events::BridgeEvent;
+use crate::mqtt_connection::MqttConnection;
+use crate::mqtt_formats::{meter_id, metric_values, topic_id};
+use crate::readings::MeterSample;
+use crate::sinks::{Sink, SinkMessage};
+use crate::SETTINGS;
+use futures::future::{join_all, BoxFuture};
+use rumqttc::{AsyncClient, Event, Incoming, Outgoing, QoS};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+
+const HOMIE_VERSION: &str = "4.0";
+const HOMIE_NODE: &str = "meter";
+const RESET_PROPERTY: &str = "energy-reset";
+
+// value ranges of the PZEM-016, published as $format
+fn property_format(metric: &str) -> Option<&'static str> {
+    match metric {
+        "volts" => Some("80:260"),
+        "current" => Some("0:100"),
+        "power" => Some("0:23000"),
+        "frequency" => Some("45:65"),
+        "power_factor" => Some("0:1"),
+        _ => None,
+    }
+}
+
+// one meter as a homie device, on its own connection so its will can mark just that meter lost
+struct HomieDevice {
+    device: PZEMDevice,
+    client: AsyncClient,
+    task: JoinHandle<()>,
+}
+
+pub struct HomieSink {
+    output: MqttOutput,
+    config: AppConfig,
+    devices: HashMap<String, HomieDevice>,
+}
+
+impl HomieSink {
+    pub fn new(output: &MqttOutput, config: &AppConfig) -> Self {
+        HomieSink {
+            output: output.clone(),
+            config: config.clone(),
+            devices: HashMap::new(),
+        }
+    }
+
+    fn topic(&self, device: &PZEMDevice, rest: &str) -> String {
+        format!("{}/{}/{rest}", self.output.base_topic(), meter_id(device))
+    }
+
+    async fn connect(&self, device: &PZEMDevice) -> Option<HomieDevice> {
+        let client_id = format!("{}-{}", self.config.client_id(), meter_id(device));
+        let will = Some((self.topic(device, "$state"), b"lost".to_vec()));
+        let mqtt = match MqttConnection::new(
+            client_id,
+            self.config.mqtt_server_addr.clone(),
+            self.config.mqtt_server_port.unwrap_or(1883),
+            self.config.mqtt_username.clone(),
+            self.config.mqtt_password.clone(),
+            will,
+        )
+        .await
+        {
+            Ok(m) => m,
+            Err(e) => {
+                error!("couldn't create homie connection for {}: {e}", device.key());
+                return None;
+            }
+        };
+        let client = mqtt.client.clone();
+        let task = tokio::task::spawn(homie_event_loop(
+            mqtt,
+            device.key(),
+            self.topic(device, "$state"),
+            self.topic(device, &format!("{HOMIE_NODE}/{RESET_PROPERTY}/set")),
+        ));
+        Some(HomieDevice {
+            device: device.clone(),
+            client,
+            task,
+        })
+    }
+
+    async fn announce(&self, homie: &HomieDevice, sample: &MeterSample) {
+        let device = &homie.device;
+        let metrics = metric_values(sample);
+        let mut properties: Vec<String> = metrics.iter().map(|(metric, ..)| topic_id(metric)).collect();
+        properties.push(RESET_PROPERTY.to_string());
+        let mut attributes: Vec<(String, String)> = vec![
+            ("$state".to_string(), "init".to_string()),
+            ("$homie".to_string(), HOMIE_VERSION.to_string()),
+            ("$name".to_string(), device.breaker.clone()),
+            ("$nodes".to_string(), HOMIE_NODE.to_string()),
+            ("$extensions".to_string(), String::new()),
+            (format!("{HOMIE_NODE}/$name"), "PZEM-016 meter".to_string()),
+            (format!("{HOMIE_NODE}/$type"), "PZEM-016".to_string()),
+            (format!("{HOMIE_NODE}/$properties"), properties.join(",")),
+        ];
+        for (metric, name, unit, _) in metrics.iter() {
+            let property = format!("{HOMIE_NODE}/{}", topic_id(metric));
+            attributes.push((format!("{property}/$name"), name.to_string()));
+            attributes.push((format!("{property}/$datatype"), "float".to_string()));
+            if let Some(unit) = unit {
+                attributes.push((format!("{property}/$unit"), unit.to_string()));
+            }
+            if let Some(format) = property_format(metric) {
+                attributes.push((format!("{property}/$format"), format.to_string()));
+            }
+        }
+        // setting this to true restarts the bridge's running energy total for the meter
+        let reset = format!("{HOMIE_NODE}/{RESET_PROPERTY}");
+        attributes.push((format!("{reset}/$name"), "reset energy total".to_string()));
+        attributes.push((format!("{reset}/$datatype"), "boolean".to_string()));
+        attributes.push((format!("{reset}/$settable"), "true".to_string()));
+        attributes.push((format!("{reset}/$retained"), "false".to_string()));
+        attributes.push(("$state".to_string(), "ready".to_string()));
+        for (attribute, value) in attributes {
+            send(&homie.client, self.topic(device, &attribute), value, true).await;
+        }
+    }
+
+    async fn handle(&mut self, sample: &MeterSample) {
+        let device = &sample.device;
+        let key = device.key();
+        let changed = match self.devices.get_mut(&key) {
+            Some(homie) if homie.device != *device => {
+                homie.device = device.clone();
+                true
+            }
+            Some(_) => false,
+            None => match self.connect(device).await {
+                Some(homie) => {
+                    self.devices.insert(key.clone(), homie);
+                    true
+                }
+                None => return,
+            },
+        };
+        let homie = match self.devices.get(&key) {
+            Some(h) => h,
+            None => return,
+        };
+        if changed {
+            self.announce(homie, sample).await;
+        }
+        for (metric, _, _, value) in metric_values(sample) {
+            if let Some(v) = value {
+                let topic = self.topic(device, &format!("{HOMIE_NODE}/{}", topic_id(metric)));
+                send(&homie.client, topic, v.to_string(), true).await;
+            }
+        }
+    }
+
+    async fn set_state(&self, meter: &str, state: &str) {
+        if let Some(homie) = self.devices.get(meter) {
+            send(&homie.client, self.topic(&homie.device, "$state"), state.to_string(), true).await;
+        }
+    }
+
+    // a clean goodbye, so the broker doesn't publish the will; together with the publish
+    // timeout this stays inside SINK_STOP_TIMEOUT_SECS
+    async fn disconnect(&self, homie: HomieDevice) {
+        send(&homie.client, self.topic(&homie.device, "$state"), "disconnected".to_string(), true).await;
+        let _ = homie.client.disconnect().await;
+        if timeout(Duration::from_secs(1), homie.task).await.is_err() {
+            warn!("homie connection for {} didn't close in time", homie.device.key());
+        }
+    }
+}
+
+async fn send(client: &AsyncClient, topic: String, payload: String, retain: bool) {
+    match timeout(Duration::from_secs(3), client.publish(topic.clone(), QoS::AtLeastOnce, retain, payload)).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => warn!("couldn't publish {topic}: {e}"),
+        Err(_) => warn!("timed out publishing {topic}"),
+    }
+}
+
+// drives one device's connection: subscribes to its settable property and brings $state back
+// to ready after the broker has published the will
+async fn homie_event_loop(mqtt: MqttConnection, meter: String, state_topic: String, set_topic: String) {
+    let client = mqtt.client.clone();
+    let mut conn = mqtt.event_loop;
+    let mut connected_before = false;
+    loop {
+        match conn.poll().await {
+            Ok(Event::Incoming(Incoming::ConnAck(_))) => {
+                if let Err(e) = client.subscribe(set_topic.clone(), QoS::AtLeastOnce).await {
+                    warn!("couldn't subscribe to {set_topic}: {e}");
+                }
+                if connected_before {
+                    let _ = client.publish(state_topic.clone(), QoS::AtLeastOnce, true, "ready").await;
+                }
+                connected_before = true;
+            }
+            Ok(Event::Incoming(Incoming::Publish(p))) if p.topic == set_topic => {
+                if p.payload.as_ref() == b"true" {
+                    info!("energy total reset requested for {meter} over homie");
+                    ENERGY_RESET_REQUESTS.write().await.insert(meter.clone());
+                }
+            }
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => return,
+            Ok(_) => {}
+            Err(e) => {
+                warn!("homie connection for {meter} failed: {e}");
+                sleep(Duration::from_secs(5)).await;
+            }
+        }
+    }
+}
+
+impl Sink for HomieSink {
+    fn name(&self) -> &'static str {
+        "mqtt_homie"
+    }
+
+    fn queue_size(&self) -> usize {
+        self.output.queue_size.unwrap_or(SINK_QUEUE_SIZE)
+    }
+
+    fn run(mut self: Box<Self>, mut rx: mpsc::Receiver<SinkMessage>) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            while let Some(msg) = rx.recv().await {
+                match msg {
+                    SinkMessage::Sample(sample) => self.handle(&sample).await,
+                    SinkMessage::Event(event) => match event.as_ref() {
+                        BridgeEvent::MeterOffline { meter, .. } => self.set_state(meter, "alert").await,
+                        BridgeEvent::MeterOnline { meter, .. } => self.set_state(meter, "ready").await,
+                        _ => {}
+                    },
+                    // meters that are no longer configured say goodbye; the rest are announced
+                    // again on their next reading in case their settings changed
+                    SinkMessage::ConfigReloaded => {
+                        self.config = SETTINGS.read().await.clone();
+                        let keys: Vec<String> = self.config.devices().iter().map(|d| d.key()).collect();
+                        let removed: Vec<String> = self.devices.keys().filter(|k| !keys.contains(k)).cloned().collect();
+                        let removed: Vec<HomieDevice> = removed.iter().filter_map(|k| self.devices.remove(k)).collect();
+                        join_all(removed.into_iter().map(|homie| self.disconnect(homie))).await;
+                    }
+                }
+            }
+            // all at once, so shutting down takes no longer with more meters
+            let devices = std::mem::take(&mut self.devices);
+            join_all(devices.into_values().map(|homie| self.disconnect(homie))).await;
+        })
+    }
+}
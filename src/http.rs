@@ -0,0 +1,316 @@
+use crate::config::AppConfig;
+use crate::file_sink::csv_field;
+use crate::history::{HistoryRow, HistoryStore};
+use crate::prometheus::render_metrics;
+use crate::readings::{MeterReading, LATEST_READINGS};
+use crate::timestamp::Timestamp;
+use crate::SETTINGS;
+use axum::extract::{Path, Query};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::time::{Instant, SystemTime};
+
+lazy_static! {
+    static ref STARTED: Instant = Instant::now();
+}
+
+type ApiError = (StatusCode, String);
+
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+    version: &'static str,
+    uptime_secs: u64,
+    meters: usize,
+}
+
+#[derive(Serialize)]
+struct LatestReading {
+    #[serde(flatten)]
+    reading: MeterReading,
+    energy_wh: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cost_today: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cost_month: Option<f64>,
+    age_secs: u64,
+}
+
+#[derive(Serialize)]
+struct MeterSummary {
+    // the breaker name, which is how meters are addressed in the api
+    id: String,
+    meter: String,
+    port: String,
+    addr: u8,
+    area: Option<String>,
+    rating_amps: Option<u16>,
+    latest: Option<LatestReading>,
+}
+
+#[derive(Deserialize)]
+struct RangeQuery {
+    from: Option<String>,
+    to: Option<String>,
+    step: Option<u64>,
+    format: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ReadingRow {
+    timestamp: Timestamp,
+    volts: f64,
+    amps: f64,
+    watts: f64,
+    watt_hours: f64,
+    frequency: f64,
+    power_factor: f64,
+    energy_wh: f64,
+    samples: i64,
+}
+
+#[derive(Serialize)]
+struct EnergyTotal {
+    name: String,
+    energy_wh: Option<f64>,
+    // groups only: members without history in the range, in which case there's no total
+    #[serde(skip_serializing_if = "Option::is_none")]
+    members_missing: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct EnergyReport {
+    from: Timestamp,
+    to: Timestamp,
+    meters: Vec<EnergyTotal>,
+    groups: Vec<EnergyTotal>,
+}
+
+pub async fn http_server(listen_addr: String) -> Result<(), std::io::Error> {
+    lazy_static::initialize(&STARTED);
+    let app = Router::new()
+        .route("/health", get(health))
+        .route("/meters", get(meters))
+        .route("/meters/:id/readings", get(meter_readings))
+        .route("/energy", get(energy))
+        .route("/metrics", get(metrics));
+    let listener = tokio::net::TcpListener::bind(&listen_addr).await?;
+    info!("HTTP server listening on {listen_addr}");
+    axum::serve(listener, app).await
+}
+
+async fn health() -> Json<Health> {
+    let meters = SETTINGS.read().await.devices().len();
+    Json(Health {
+        status: "ok",
+        version: env!("CARGO_PKG_VERSION"),
+        uptime_secs: STARTED.elapsed().as_secs(),
+        meters,
+    })
+}
+
+async fn meters() -> Json<Vec<MeterSummary>> {
+    let config = SETTINGS.read().await.clone();
+    let latest = LATEST_READINGS.read().await;
+    Json(
+        config
+            .devices()
+            .iter()
+            .map(|d| MeterSummary {
+                id: d.breaker.clone(),
+                meter: d.key(),
+                port: d.port.clone(),
+                addr: d.addr,
+                area: d.area.clone(),
+                rating_amps: d.rating_amps,
+                latest: latest.get(&d.key()).map(|c| LatestReading {
+                    reading: c.reading,
+                    energy_wh: c.energy_wh,
+                    cost_today: c.cost_today,
+                    cost_month: c.cost_month,
+                    age_secs: c.age_secs(),
+                }),
+            })
+            .collect(),
+    )
+}
+
+async fn meter_readings(Path(id): Path<String>, Query(q): Query<RangeQuery>) -> Result<Response, ApiError> {
+    let config = SETTINGS.read().await.clone();
+    let meter = config.device_by_breaker(&id).map_err(|e| (StatusCode::NOT_FOUND, e))?.key();
+    let to = parse_time(q.to.as_deref(), config.local_timezone())?.unwrap_or_else(Utc::now);
+    let from = parse_time(q.from.as_deref(), config.local_timezone())?.unwrap_or(to - Duration::hours(1));
+    check_range(from, to)?;
+    let store = history_store(&config)?;
+    let step = q.step;
+    let rows = tokio::task::spawn_blocking(move || store.readings(&meter, from, to, step))
+        .await
+        .map_err(internal_error)?
+        .map_err(internal_error)?;
+    let format = config.timestamp_format();
+    let rows: Vec<ReadingRow> = rows
+        .into_iter()
+        .map(|r: HistoryRow| ReadingRow {
+            timestamp: format.format(to_system_time(r.ts_millis)),
+            volts: r.volts,
+            amps: r.amps,
+            watts: r.watts,
+            watt_hours: r.watt_hours,
+            frequency: r.frequency,
+            power_factor: r.power_factor,
+            energy_wh: r.energy_wh,
+            samples: r.samples,
+        })
+        .collect();
+    if wants_csv(&q) {
+        let mut csv = String::from("timestamp,volts,amps,watts,watt_hours,frequency,power_factor,energy_wh,samples\n");
+        for r in rows.iter() {
+            csv.push_str(&format!(
+                "{},{},{},{},{},{},{},{},{}\n",
+                timestamp_text(&r.timestamp),
+                r.volts,
+                r.amps,
+                r.watts,
+                r.watt_hours,
+                r.frequency,
+                r.power_factor,
+                r.energy_wh,
+                r.samples
+            ));
+        }
+        return Ok(csv_response(csv));
+    }
+    Ok(Json(rows).into_response())
+}
+
+// energy used by every meter and group between `from` (required) and `to` (now if unset)
+async fn energy(Query(q): Query<RangeQuery>) -> Result<Response, ApiError> {
+    let config = SETTINGS.read().await.clone();
+    let from = parse_time(q.from.as_deref(), config.local_timezone())?
+        .ok_or((StatusCode::BAD_REQUEST, "from is required".to_string()))?;
+    let to = parse_time(q.to.as_deref(), config.local_timezone())?.unwrap_or_else(Utc::now);
+    check_range(from, to)?;
+    let store = history_store(&config)?;
+    let devices = config.devices().to_vec();
+    let meters = tokio::task::spawn_blocking(move || {
+        devices
+            .iter()
+            .map(|d| Ok((d.breaker.clone(), store.energy_between(&d.key(), from, to)?)))
+            .collect::<rusqlite::Result<Vec<(String, Option<f64>)>>>()
+    })
+    .await
+    .map_err(internal_error)?
+    .map_err(internal_error)?;
+    let groups: Vec<EnergyTotal> = config
+        .groups()
+        .iter()
+        .map(|g| {
+            let member_totals: Vec<Option<f64>> = meters
+                .iter()
+                .filter(|(breaker, _)| g.members.contains(breaker))
+                .map(|(_, wh)| *wh)
+                .collect();
+            let missing = g.members.len() - member_totals.iter().flatten().count();
+            // a partial sum would pass for the whole group's use
+            EnergyTotal {
+                name: g.name.clone(),
+                energy_wh: if missing == 0 { Some(member_totals.iter().flatten().sum()) } else { None },
+                members_missing: Some(missing),
+            }
+        })
+        .collect();
+    let meters: Vec<EnergyTotal> = meters
+        .into_iter()
+        .map(|(name, energy_wh)| EnergyTotal {
+            name,
+            energy_wh,
+            members_missing: None,
+        })
+        .collect();
+    if wants_csv(&q) {
+        let mut csv = String::from("kind,name,energy_wh\n");
+        for (kind, totals) in [("meter", &meters), ("group", &groups)] {
+            for t in totals.iter() {
+                let wh = t.energy_wh.map(|wh| wh.to_string()).unwrap_or_default();
+                csv.push_str(&format!("{kind},{},{wh}\n", csv_field(&t.name)));
+            }
+        }
+        return Ok(csv_response(csv));
+    }
+    let format = config.timestamp_format();
+    Ok(Json(EnergyReport {
+        from: format.format(from.into()),
+        to: format.format(to.into()),
+        meters,
+        groups,
+    })
+    .into_response())
+}
+
+async fn metrics() -> Response {
+    match render_metrics().await {
+        Some(body) => ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response(),
+        None => (StatusCode::NOT_FOUND, "prometheus is not enabled").into_response(),
+    }
+}
+
+fn history_store(config: &AppConfig) -> Result<HistoryStore, ApiError> {
+    let history = config
+        .history
+        .as_ref()
+        .ok_or((StatusCode::NOT_FOUND, "history is not enabled".to_string()))?;
+    HistoryStore::open_read_only(&history.path(config))
+        .map_err(|e| (StatusCode::SERVICE_UNAVAILABLE, format!("history database unavailable: {e}")))
+}
+
+// RFC 3339, or a plain date meaning local midnight at the start of that day
+fn parse_time(s: Option<&str>, tz: Tz) -> Result<Option<DateTime<Utc>>, ApiError> {
+    let s = match s {
+        Some(s) => s,
+        None => return Ok(None),
+    };
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Ok(Some(t.with_timezone(&Utc)));
+    }
+    NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .ok()
+        .and_then(|d| tz.from_local_datetime(&d.and_time(chrono::NaiveTime::MIN)).earliest())
+        .map(|t| Some(t.with_timezone(&Utc)))
+        .ok_or((StatusCode::BAD_REQUEST, format!("{s} is not an RFC 3339 timestamp or YYYY-MM-DD date")))
+}
+
+fn check_range(from: DateTime<Utc>, to: DateTime<Utc>) -> Result<(), ApiError> {
+    if from >= to {
+        return Err((StatusCode::BAD_REQUEST, "from must be before to".to_string()));
+    }
+    Ok(())
+}
+
+fn wants_csv(q: &RangeQuery) -> bool {
+    q.format.as_deref() == Some("csv")
+}
+
+fn csv_response(body: String) -> Response {
+    ([(header::CONTENT_TYPE, "text/csv; charset=utf-8")], body).into_response()
+}
+
+fn timestamp_text(t: &Timestamp) -> String {
+    match t {
+        Timestamp::Text(s) => s.clone(),
+        Timestamp::Epoch(n) => n.to_string(),
+    }
+}
+
+fn to_system_time(ts_millis: i64) -> SystemTime {
+    Utc.timestamp_millis_opt(ts_millis).single().unwrap_or_default().into()
+}
+
+fn internal_error(e: impl std::fmt::Display) -> ApiError {
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
@@ -0,0 +1,202 @@
+use crate::config::Influx;
+use crate::consts::SINK_QUEUE_SIZE;
+use crate::readings::MeterSample;
+use crate::sinks::{Sink, SinkMessage};
+use futures::future::BoxFuture;
+use std::time::Duration;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::{interval, sleep};
+
+const MEASUREMENT: &str = "pzem016";
+// keep udp datagrams under a typical path mtu
+const UDP_MAX_PAYLOAD: usize = 1400;
+
+// commas, spaces and equals signs have to be escaped in tag keys and values
+fn escape_tag(s: &str) -> String {
+    s.replace('\\', "\\\\").replace(',', "\\,").replace(' ', "\\ ").replace('=', "\\=")
+}
+
+pub fn line_protocol(r: &MeterSample) -> String {
+    format!(
+        "{MEASUREMENT},address={},breaker={},port={} volts={},amps={},watts={},wh={},hz={},pf={} {}",
+        r.device.addr,
+        escape_tag(&r.device.breaker),
+        escape_tag(&r.device.port),
+        r.reading.volts,
+        r.reading.amps,
+        r.reading.watts,
+        r.reading.watt_hours,
+        r.reading.frequency,
+        r.reading.power_factor,
+        r.at.timestamp_nanos_opt().unwrap_or_default(),
+    )
+}
+
+struct InfluxWriter {
+    settings: Influx,
+    http: reqwest::Client,
+    udp: Option<UdpSocket>,
+}
+
+impl InfluxWriter {
+    async fn new(settings: &Influx) -> Self {
+        let udp = match &settings.udp_addr {
+            Some(addr) => match UdpSocket::bind("0.0.0.0:0").await {
+                Ok(sock) => match sock.connect(addr).await {
+                    Ok(_) => Some(sock),
+                    Err(e) => {
+                        error!("influx udp output disabled, couldn't resolve {addr}: {e}");
+                        None
+                    }
+                },
+                Err(e) => {
+                    error!("influx udp output disabled: {e}");
+                    None
+                }
+            },
+            None => None,
+        };
+        // without a timeout a hung connection would stall the sink for good
+        let http = match reqwest::Client::builder().timeout(Duration::from_secs(settings.timeout_secs())).build() {
+            Ok(c) => c,
+            Err(e) => {
+                error!("couldn't apply influx timeout: {e}");
+                reqwest::Client::new()
+            }
+        };
+        InfluxWriter {
+            settings: settings.clone(),
+            http,
+            udp,
+        }
+    }
+
+    async fn write(&self, lines: &[String]) {
+        if let Some(url) = &self.settings.url {
+            self.write_http(url, lines).await;
+        }
+        if let Some(sock) = &self.udp {
+            // pack as many whole lines as fit into each datagram
+            let mut packet = String::new();
+            for line in lines {
+                if !packet.is_empty() && packet.len() + line.len() + 1 > UDP_MAX_PAYLOAD {
+                    if let Err(e) = sock.send(packet.as_bytes()).await {
+                        warn!("influx udp send failed: {e}");
+                    }
+                    packet.clear();
+                }
+                packet.push_str(line);
+                packet.push('\n');
+            }
+            if !packet.is_empty() {
+                if let Err(e) = sock.send(packet.as_bytes()).await {
+                    warn!("influx udp send failed: {e}");
+                }
+            }
+        }
+        if let Some(path) = &self.settings.line_file {
+            let body = lines.join("\n") + "\n";
+            let result = match OpenOptions::new().create(true).append(true).open(path).await {
+                Ok(mut f) => f.write_all(body.as_bytes()).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                error!("couldn't append influx lines to {path}: {e}");
+            }
+        }
+    }
+
+    async fn write_http(&self, url: &str, lines: &[String]) {
+        let endpoint = format!("{}/api/v2/write", url.trim_end_matches('/'));
+        let query = [
+            ("org", self.settings.org.clone().unwrap_or_default()),
+            ("bucket", self.settings.bucket.clone().unwrap_or_default()),
+            ("precision", "ns".to_string()),
+        ];
+        let body = lines.join("\n");
+        let mut backoff = Duration::from_secs(1);
+        for attempt in 0..=self.settings.max_retries() {
+            let mut request = self.http.post(&endpoint).query(&query).body(body.clone());
+            if let Some(token) = &self.settings.token {
+                request = request.header("Authorization", format!("Token {token}"));
+            }
+            match request.send().await {
+                Ok(resp) if resp.status().is_success() => return,
+                // the server understood and refused the data, retrying won't help
+                Ok(resp) if resp.status().is_client_error() && resp.status().as_u16() != 429 => {
+                    let status = resp.status();
+                    let text = resp.text().await.unwrap_or_default();
+                    error!("influx rejected {} lines ({status}): {text}", lines.len());
+                    return;
+                }
+                Ok(resp) => warn!("influx write attempt {} failed: {}", attempt + 1, resp.status()),
+                Err(e) => warn!("influx write attempt {} failed: {e}", attempt + 1),
+            }
+            if attempt < self.settings.max_retries() {
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(Duration::from_secs(60));
+            }
+        }
+        error!("giving up on {} influx lines after {} retries", lines.len(), self.settings.max_retries());
+    }
+}
+
+pub struct InfluxSink {
+    settings: Influx,
+}
+
+impl InfluxSink {
+    pub fn new(settings: &Influx) -> Self {
+        InfluxSink { settings: settings.clone() }
+    }
+}
+
+impl Sink for InfluxSink {
+    fn name(&self) -> &'static str {
+        "influx"
+    }
+
+    fn queue_size(&self) -> usize {
+        self.settings.queue_size.unwrap_or(SINK_QUEUE_SIZE)
+    }
+
+    fn run(self: Box<Self>, rx: mpsc::Receiver<SinkMessage>) -> BoxFuture<'static, ()> {
+        Box::pin(influx_loop(rx, self.settings))
+    }
+}
+
+async fn influx_loop(mut rx: mpsc::Receiver<SinkMessage>, settings: Influx) {
+    let writer = InfluxWriter::new(&settings).await;
+    let mut batch: Vec<String> = Vec::with_capacity(settings.batch_size());
+    let mut flush = interval(Duration::from_secs(settings.flush_interval_secs()));
+    loop {
+        select! {
+            r = rx.recv() => match r {
+                Some(SinkMessage::Sample(r)) => {
+                    batch.push(line_protocol(&r));
+                    if batch.len() < settings.batch_size() {
+                        continue;
+                    }
+                }
+                Some(SinkMessage::ConfigReloaded | SinkMessage::Event(_)) => continue,
+                None => {
+                    if !batch.is_empty() {
+                        writer.write(&batch).await;
+                    }
+                    debug!("influx channel closed, stopping influx task");
+                    return;
+                }
+            },
+            _ = flush.tick() => {
+                if batch.is_empty() {
+                    continue;
+                }
+            }
+        }
+        writer.write(&batch).await;
+        batch.clear();
+    }
+}
@@ -1,3 +1,5 @@
+use crate::events::BridgeEvent;
+use crate::readings::MeterSample;
 use crate::payload::Payload;
 
 #[derive(Clone)]
@@ -12,6 +14,7 @@ pub struct InboundMessage {
 pub struct PublishMessage {
     pub(crate) topic: String,
     pub(crate) payload: Payload,
+    pub(crate) retain: bool,
 }
 
 #[derive(Clone)]
@@ -27,5 +30,8 @@ pub enum IPCMessage {
     Outbound(PublishMessage),
     PleaseReconnect(String, u8),
     Error(IPCError),
+    ConfigReloaded,
+    Reading(MeterSample),
+    Event(BridgeEvent),
     Shutdown,
 }
@@ -5,45 +5,73 @@ mod config;
 mod mqtt_poll;
 mod payload;
 mod ipc;
+mod reload;
+mod discovery;
+mod http;
+mod timestamp;
+mod readings;
+mod groups;
+mod energy_periods;
+mod state;
+mod events;
+mod energy_tracker;
+mod tariffs;
+mod rules;
+mod detectors;
+mod power_quality;
+mod analysis;
+mod history;
+mod influx;
+mod sinks;
+mod mqtt_sink;
+mod mqtt_formats;
+mod homie;
+mod sparkplug;
+mod poller;
+mod prometheus;
+mod file_sink;
+mod graphite;
+mod webhook;
 
 #[macro_use] extern crate tokio;
 #[macro_use] extern crate tracing;
 
-use std::fs;
-use crate::config::AppConfig;
+use std::collections::HashMap;
+use crate::config::{config_file_path, AppConfig, PZEMDevice};
 use lazy_static::lazy_static;
 use std::process;
+use std::sync::Arc;
 use std::thread::sleep;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tracing_subscriber::filter::EnvFilter;
-use tokio::sync::{broadcast, mpsc, RwLock, OnceCell};
+use tokio::sync::{broadcast, mpsc, Mutex, RwLock, OnceCell};
 use pzem016lib::PZEM;
-use tokio::sync::mpsc::error::TryRecvError;
-use crate::consts::{MPSC_BUFFER_SIZE, POLL_TIME};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::error::{TryRecvError, TrySendError};
+use tokio::task::JoinHandle;
+use crate::consts::{MPSC_BUFFER_SIZE, MQTT_POLL_INTERVAL_MILLIS, POLL_TIME};
+use crate::poller::{poll_meter, SharedPort};
+use crate::sinks::SinkSet;
 use crate::ipc::{IPCMessage, PublishMessage};
 use crate::mqtt_connection::MqttConnection;
 use crate::mqtt_poll::mqtt_poll_loop;
-use crate::payload::{generate_payloads, Payload};
+use crate::discovery::DiscoveryRegistry;
+use crate::groups::group_loop;
+use crate::http::http_server;
+use crate::readings::LATEST_READINGS;
+use crate::payload::{bridge_state, bridge_status_topic, expected_discovery_topics, legacy_discovery_topics, publish_bridge_discovery, Payload};
+use crate::reload::config_watch_loop;
 
 
 lazy_static! {
     static ref SHUTDOWN: OnceCell<bool> = OnceCell::new();
         //region create SETTINGS static object
     static ref SETTINGS: RwLock<AppConfig> = RwLock::new({
-         let cfg_file = match std::env::var("CONFIG_FILE_PATH") {
-             Ok(s) => s,
-             Err(_e) => { "./config.yaml".to_string()}
-         };
-        let yaml = fs::read_to_string(cfg_file).unwrap_or_else(|e| {
-            die(&format!("Can't read config file: {e}"));
-            String::default()
-            });
-        let gc: AppConfig = match serde_yaml::from_str(&yaml)  {
+        match AppConfig::load(&config_file_path()) {
             Ok(gc) => gc,
-            Err(e) => { die(&format!("Couldn't deserialize AppConfig: {e}"));
+            Err(e) => { die(&e.to_string());
             AppConfig::default()}
-        };
-        gc
+        }
     });
     //endregion
 }
@@ -54,91 +82,248 @@ pub async fn main() {
         .with_env_filter(EnvFilter::from_default_env())
         .init();
 //region create mqtt server connection and spawn mqtt thread
-    let config = SETTINGS.read().await;
-    let mqtt_conn = match MqttConnection::new(
-        config
-            .mqtt_client_id
-            .clone()
-            .unwrap_or("pzem016mqtt".to_string()),
-        config.mqtt_server_addr.clone(),
-        config.mqtt_server_port.unwrap_or(1883),
-        config.mqtt_username.clone(),
-        config.mqtt_password.clone(),
-    )
-        .await
-    {
-        Ok(m) => m,
-        Err(_e) => {
-            return die("Couldn't create mqtt connection object: {e}");
-        }
-    };
-
+    let mut config = SETTINGS.read().await.clone();
     let (tx, mut rx) = mpsc::channel::<IPCMessage>(MPSC_BUFFER_SIZE);
-    let (mqtt_tx, mqtt_rx) =mpsc::channel::<IPCMessage>(MPSC_BUFFER_SIZE);
+    let (mqtt_tx, mut mqtt_rx) =mpsc::channel::<IPCMessage>(MPSC_BUFFER_SIZE);
     let (from_mqtt_tx, mut from_mqtt_rx) = mpsc::channel::<IPCMessage>(MPSC_BUFFER_SIZE);
     let (broadcast_tx, _broadcast_rx) = broadcast::channel::<IPCMessage>(16_usize);
 
-    let bcasttx = broadcast_tx.clone();
-    let mut mqtt_handler = tokio::task::spawn(async move {
-        let _ = mqtt_poll_loop(
-            mqtt_conn,
-            mqtt_rx,
-            bcasttx.clone().subscribe(),
-            from_mqtt_tx,
+    let mut mqtt_handler = if config.mqtt_enabled() {
+        let last_will = match serde_json::to_vec(&Payload::CurrentState(bridge_state(false, config.timestamp_format()))) {
+            Ok(p) => Some((bridge_status_topic(&config), p)),
+            Err(e) => {
+                return die(&format!("Couldn't serialize last will: {e}"));
+            }
+        };
+        let mqtt_conn = match MqttConnection::new(
+            config.client_id(),
+            config.mqtt_server_addr.clone(),
+            config.mqtt_server_port.unwrap_or(1883),
+            config.mqtt_username.clone(),
+            config.mqtt_password.clone(),
+            last_will,
         )
-            .await;
-    });
+            .await
+        {
+            Ok(m) => m,
+            Err(_e) => {
+                return die("Couldn't create mqtt connection object: {e}");
+            }
+        };
+        let bcasttx = broadcast_tx.clone();
+        tokio::task::spawn(async move {
+            let _ = mqtt_poll_loop(
+                mqtt_conn,
+                mqtt_rx,
+                bcasttx.clone().subscribe(),
+                from_mqtt_tx,
+            )
+                .await;
+        })
+    } else {
+        // no broker configured; whatever would have been published is discarded
+        info!("mqtt_server_addr is not set, running without mqtt");
+        tokio::task::spawn(async move {
+            while mqtt_rx.recv().await.is_some() {}
+        })
+    };
     //endregion
 
-    let mut pzem = PZEM::new("10.174.2.48:4196".to_string()).await.expect("couldn't connect to modbus");
-    let pzem_tx = tx.clone();
-    let mut pzem_pzem = pzem.clone();
-    let mut pzem_handler = tokio::task::spawn(async move {
-        let _ = generate_payloads(&mut pzem_pzem,pzem_tx).await;
+    let http_addr = config.http_listen_addr().to_string();
+    let _http_handler = tokio::task::spawn(async move {
+        if let Err(e) = http_server(http_addr).await {
+            error!("HTTP server stopped: {e}");
+        }
     });
 
-    let _ = ctrlc::set_handler(move || {
+    let mut registry = DiscoveryRegistry::load(config.state_dir());
+    // meters used to be announced by modbus address alone
+    registry.adopt(legacy_discovery_topics(&config));
+    registry.prune(&expected_discovery_topics(&config), &mqtt_tx).await;
+    if let Err(e) = publish_bridge_discovery(&config, &tx).await {
+        error!("Couldn't publish bridge discovery: {e}");
+    }
+
+    let mut sinks = SinkSet::start(&config, &tx);
+
+    let mut devices: Vec<PZEMDevice> = config.devices().to_vec();
+    let mut pzems: HashMap<String, SharedPort> = HashMap::new();
+    let mut pollers: HashMap<PZEMDevice, JoinHandle<()>> = HashMap::new();
+    for device in devices.iter() {
+        if let Some(h) = spawn_poller(device, &mut pzems, &tx).await {
+            pollers.insert(device.clone(), h);
+        }
+    }
+
+    let mut group_handler = spawn_group_loop(&tx);
+
+    let reload_tx = tx.clone();
+    let _reload_handler = tokio::task::spawn(async move {
+        if let Err(e) = config_watch_loop(reload_tx).await {
+            error!("Config watcher stopped, hot reload is unavailable: {e}");
+        }
+    });
+
+    tokio::task::spawn(async move {
+        wait_for_shutdown_signal().await;
         println!("Received shutdown signal, communicating to threads to stop");
         let _ = SHUTDOWN.set(true);
     });
+    let mut last_health_check = Instant::now();
+    // unretained messages dropped since mqtt last kept up
+    let mut dropped_outbound: u64 = 0;
     loop {
-        if let Some(shutting_down) = SHUTDOWN.get() {
+        if SHUTDOWN.get().is_some() {
                     break;
         }
         // check thread health
-        if pzem_handler.is_finished() {
-            warn!("pzem_handler was finished, restarting.");
-            let my_tx = tx.clone();
-            let mut my_pzem = pzem.clone();
-            pzem_handler = tokio::task::spawn(async move {
-                let _ = generate_payloads(&mut my_pzem, my_tx).await;
-            });
+        if last_health_check.elapsed() > Duration::from_secs(POLL_TIME as u64) {
+            last_health_check = Instant::now();
+            for device in devices.iter() {
+                if pollers.get(device).map_or(true, |h| h.is_finished()) {
+                    warn!("poller for {} ({}) was finished, restarting.", device.key(), device.breaker);
+                    if let Some(h) = spawn_poller(device, &mut pzems, &tx).await {
+                        pollers.insert(device.clone(), h);
+                    }
+                }
+            }
+            if group_handler.is_finished() {
+                warn!("group_handler was finished, restarting.");
+                group_handler = spawn_group_loop(&tx);
+            }
         }
         match rx.try_recv() {
             Ok(ipcm) => {
                 match ipcm {
                     IPCMessage::Inbound(_) => {}
                     IPCMessage::Outbound(o) => {
-                        if let Err(e) = mqtt_tx.send(
-                            IPCMessage::Outbound(o)
-                        ).await {
-                            die(&e.to_string());
+                        if o.retain {
+                            if let Payload::Config(_) = o.payload {
+                                registry.record(&o.topic);
+                            }
+                        }
+                        // never waits on the broker, or a backlog there would hold up polling;
+                        // retained messages are state that has to arrive, so they wait in a task
+                        let retain = o.retain;
+                        match mqtt_tx.try_send(IPCMessage::Outbound(o)) {
+                            Ok(_) => {
+                                if dropped_outbound > 0 {
+                                    info!("mqtt caught up after dropping {dropped_outbound} messages");
+                                    dropped_outbound = 0;
+                                }
+                            }
+                            Err(TrySendError::Full(msg)) if retain => {
+                                let my_mqtt_tx = mqtt_tx.clone();
+                                tokio::task::spawn(async move {
+                                    let _ = my_mqtt_tx.send(msg).await;
+                                });
+                            }
+                            Err(TrySendError::Full(_)) => {
+                                if dropped_outbound == 0 {
+                                    warn!("mqtt is falling behind, dropping messages");
+                                }
+                                dropped_outbound += 1;
+                            }
+                            Err(TrySendError::Closed(_)) => {
+                                die("mqtt channel closed");
+                            }
                         }
                     }
+                    IPCMessage::ConfigReloaded => {
+                        let new_config = SETTINGS.read().await.clone();
+                        let new_devices = new_config.devices().to_vec();
+                        // pollers only read their settings at startup, so some changes restart all of them
+                        let restart_all = config.poller_settings_changed(&new_config);
+                        for removed in devices.iter().filter(|d| restart_all || !new_devices.contains(d)) {
+                            info!("stopping poller for {} ({})", removed.key(), removed.breaker);
+                            if let Some(h) = pollers.remove(removed) {
+                                h.abort();
+                            }
+                            if !new_devices.iter().any(|d| d.key() == removed.key()) {
+                                LATEST_READINGS.write().await.remove(&removed.key());
+                            }
+                        }
+                        for added in new_devices.iter().filter(|d| restart_all || !devices.contains(d)) {
+                            info!("starting poller for {} ({})", added.key(), added.breaker);
+                            if let Some(h) = spawn_poller(added, &mut pzems, &tx).await {
+                                pollers.insert(added.clone(), h);
+                            }
+                        }
+                        sinks.config_reloaded();
+                        registry.prune(&expected_discovery_topics(&new_config), &mqtt_tx).await;
+                        pzems.retain(|port, _| new_devices.iter().any(|d| &d.port == port));
+                        devices = new_devices;
+                        config = new_config;
+                    }
+                    IPCMessage::Reading(sample) => {
+                        sinks.offer(sample);
+                    }
+                    IPCMessage::Event(event) => {
+                        sinks.event(event);
+                    }
                     IPCMessage::PleaseReconnect(_, _) => {}
                     IPCMessage::Error(_) => {}
                     IPCMessage::Shutdown => {}
                 }
             }
             Err(e) => match e {
-                TryRecvError::Empty => {}
+                TryRecvError::Empty => {
+                    tokio::time::sleep(Duration::from_millis(MQTT_POLL_INTERVAL_MILLIS)).await;
+                }
                 TryRecvError::Disconnected => {
                     error!("We are disconnected!");
                 }
             },
 
         }
-        //sleep(tokio::time::Duration::from_millis(100_u64));
+    }
+    sinks.stop().await;
+}
+
+// one modbus connection is shared by every meter on the same port, and the pollers take turns on it
+async fn spawn_poller(
+    device: &PZEMDevice,
+    pzems: &mut HashMap<String, SharedPort>,
+    tx: &mpsc::Sender<IPCMessage>,
+) -> Option<JoinHandle<()>> {
+    let pzem = match pzems.get(&device.port) {
+        Some(p) => p.clone(),
+        None => match PZEM::new(device.port.clone()).await {
+            Ok(p) => {
+                let p = Arc::new(Mutex::new(p));
+                pzems.insert(device.port.clone(), p.clone());
+                p
+            }
+            Err(e) => {
+                error!("couldn't connect to modbus at {}: {e}", device.port);
+                return None;
+            }
+        },
+    };
+    let my_tx = tx.clone();
+    let my_device = device.clone();
+    Some(tokio::task::spawn(async move {
+        let _ = poll_meter(pzem, my_device, my_tx).await;
+    }))
+}
+
+fn spawn_group_loop(tx: &mpsc::Sender<IPCMessage>) -> JoinHandle<()> {
+    let my_tx = tx.clone();
+    tokio::task::spawn(async move {
+        let _ = group_loop(my_tx).await;
+    })
+}
+
+async fn wait_for_shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(s) => s,
+        Err(e) => {
+            return die(&format!("Couldn't install SIGTERM handler: {e}"));
+        }
+    };
+    select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
     }
 }
 
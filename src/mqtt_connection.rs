@@ -1,5 +1,5 @@
 use crate::consts::*;
-use rumqttc::{AsyncClient, EventLoop, MqttOptions, QoS};
+use rumqttc::{AsyncClient, EventLoop, LastWill, MqttOptions, QoS};
 use std::fmt::{Debug, Formatter};
 use std::ops::{Deref, DerefMut};
 use tokio::time::Duration;
@@ -47,9 +47,13 @@ impl MqttConnection {
         port: u16,
         username: Option<String>,
         password: Option<String>,
+        last_will: Option<(String, Vec<u8>)>,
     ) -> Result<Self, MQTTError> {
         let mut mqttoptions = MqttOptions::new(&client, &addr, port);
         mqttoptions.set_keep_alive(Duration::from_secs(MQTT_KEEPALIVE_TIME));
+        if let Some((topic, payload)) = last_will {
+            mqttoptions.set_last_will(LastWill::new(topic, payload, QoS::AtLeastOnce, true));
+        }
         if username.is_some() && password.is_some() {
             mqttoptions.set_credentials(username.clone().unwrap(), password.clone().unwrap());
         }
@@ -0,0 +1,183 @@
+use crate::config::{AppConfig, MqttFormat, MqttOutput, PZEMDevice};
+use crate::consts::SINK_QUEUE_SIZE;
+use crate::energy_tracker::CONTINUOUS_ENERGY_METRIC;
+use crate::groups::slug;
+use crate::ipc::IPCMessage;
+use crate::payload::{device_metrics, device_serial, publish, Payload};
+use crate::readings::MeterSample;
+use crate::sinks::{Sink, SinkMessage};
+use crate::sparkplug::sparkplug_topic;
+use crate::SETTINGS;
+use futures::future::BoxFuture;
+use pzem016lib::errors::PZEMError;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use tokio::sync::mpsc;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SparkplugMetric {
+    pub name: String,
+    pub timestamp: i64,
+    #[serde(rename = "dataType")]
+    pub datatype: String,
+    pub value: Value,
+}
+
+// the shape of a Sparkplug B payload, for consumers that take JSON rather than protobuf
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SparkplugJsonPayload {
+    pub timestamp: i64,
+    pub seq: u64,
+    pub metrics: Vec<SparkplugMetric>,
+}
+
+// (metric, name, unit, value) of everything published for one reading
+pub type MetricValue = (&'static str, &'static str, Option<&'static str>, Option<f64>);
+
+pub fn metric_values(sample: &MeterSample) -> Vec<MetricValue> {
+    device_metrics(&sample.device)
+        .map(|m| (m.metric, m.name, m.uom, (m.value)(&sample.reading, &sample.device).map(|v| v as f64)))
+        .chain(std::iter::once((CONTINUOUS_ENERGY_METRIC, CONTINUOUS_ENERGY_METRIC, Some("Wh"), Some(sample.energy_total_wh))))
+        .collect()
+}
+
+// lowercase letters, digits and hyphens, which suits both homie ids and sparkplug topic levels
+pub fn topic_id(s: &str) -> String {
+    slug(s).replace('_', "-")
+}
+
+// unique per meter, unlike the breaker name
+pub fn meter_id(device: &PZEMDevice) -> String {
+    format!("pzem016-{}", topic_id(&device_serial(device)))
+}
+
+// a plain or sparkplug_json output from mqtt_outputs
+pub struct MqttFormatSink {
+    output: MqttOutput,
+    config: AppConfig,
+    tx: mpsc::Sender<IPCMessage>,
+    // devices as they were when last born, for sparkplug
+    announced: HashMap<String, PZEMDevice>,
+    // sparkplug sequence number, shared by every message from the edge node
+    seq: u64,
+    node_born: bool,
+}
+
+impl MqttFormatSink {
+    pub fn new(output: &MqttOutput, config: &AppConfig, tx: mpsc::Sender<IPCMessage>) -> Self {
+        MqttFormatSink {
+            output: output.clone(),
+            config: config.clone(),
+            tx,
+            announced: HashMap::new(),
+            seq: 0,
+            node_born: false,
+        }
+    }
+
+    async fn handle(&mut self, sample: &MeterSample) -> Result<(), PZEMError> {
+        let device = &sample.device;
+        let announce = self.announced.get(&device.key()).map_or(true, |d| d != device);
+        match self.output.format {
+            MqttFormat::Plain => self.publish_plain(sample).await?,
+            // these have sinks of their own
+            MqttFormat::Homie | MqttFormat::Sparkplug => {}
+            MqttFormat::SparkplugJson => self.publish_sparkplug(sample, announce).await?,
+        }
+        self.announced.insert(device.key(), device.clone());
+        Ok(())
+    }
+
+    async fn publish_plain(&self, sample: &MeterSample) -> Result<(), PZEMError> {
+        let base = format!("{}/{}", self.output.base_topic(), meter_id(&sample.device));
+        for (metric, _, _, value) in metric_values(sample) {
+            if let Some(v) = value {
+                publish(&self.tx, format!("{base}/{metric}"), Payload::Raw(v.to_string()), false).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn sparkplug_topic(&self, message_type: &str, device: Option<&PZEMDevice>) -> String {
+        sparkplug_topic(&self.output, &self.config, message_type, device)
+    }
+
+    // sequence numbers wrap at 256 and every message from the node takes the next one
+    fn next_seq(&mut self) -> u64 {
+        let seq = self.seq;
+        self.seq = (self.seq + 1) % 256;
+        seq
+    }
+
+    async fn publish_sparkplug(&mut self, sample: &MeterSample, announce: bool) -> Result<(), PZEMError> {
+        let timestamp = sample.at.timestamp_millis();
+        if !self.node_born {
+            self.seq = 0;
+            let birth = SparkplugJsonPayload {
+                timestamp,
+                seq: self.next_seq(),
+                metrics: vec![SparkplugMetric {
+                    name: "Node Control/Rebirth".to_string(),
+                    timestamp,
+                    datatype: "Boolean".to_string(),
+                    value: Value::Bool(false),
+                }],
+            };
+            publish(&self.tx, self.sparkplug_topic("NBIRTH", None), Payload::SparkplugJson(birth), false).await?;
+            self.node_born = true;
+        }
+        let metrics = metric_values(sample)
+            .into_iter()
+            .map(|(metric, _, _, value)| SparkplugMetric {
+                name: metric.to_string(),
+                timestamp,
+                datatype: "Double".to_string(),
+                value: value.map(Value::from).unwrap_or(Value::Null),
+            })
+            .collect();
+        let payload = SparkplugJsonPayload {
+            timestamp,
+            seq: self.next_seq(),
+            metrics,
+        };
+        let message_type = if announce { "DBIRTH" } else { "DDATA" };
+        let topic = self.sparkplug_topic(message_type, Some(&sample.device));
+        publish(&self.tx, topic, Payload::SparkplugJson(payload), false).await
+    }
+}
+
+impl Sink for MqttFormatSink {
+    fn name(&self) -> &'static str {
+        match self.output.format {
+            MqttFormat::Plain => "mqtt_plain",
+            MqttFormat::Homie => "mqtt_homie",
+            MqttFormat::SparkplugJson => "mqtt_sparkplug_json",
+            MqttFormat::Sparkplug => "mqtt_sparkplug",
+        }
+    }
+
+    fn queue_size(&self) -> usize {
+        self.output.queue_size.unwrap_or(SINK_QUEUE_SIZE)
+    }
+
+    fn run(mut self: Box<Self>, mut rx: mpsc::Receiver<SinkMessage>) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            while let Some(msg) = rx.recv().await {
+                match msg {
+                    SinkMessage::Sample(sample) => {
+                        if let Err(e) = self.handle(&sample).await {
+                            error!("{} sink couldn't publish reading for {}: {e}", self.name(), sample.device.key());
+                        }
+                    }
+                    SinkMessage::Event(_) => {}
+                    // every meter is announced again on its next reading
+                    SinkMessage::ConfigReloaded => {
+                        self.announced.clear();
+                        self.config = SETTINGS.read().await.clone();
+                    }
+                }
+            }
+        })
+    }
+}
@@ -92,63 +92,75 @@ pub async fn mqtt_poll_loop(
                 IPCMessage::Outbound(_) => {}
                 IPCMessage::PleaseReconnect(_, _) => {}
                 IPCMessage::Error(_) => {}
+                IPCMessage::ConfigReloaded => {}
+                IPCMessage::Reading(_) => {}
+                IPCMessage::Event(_) => {}
             },
             Err(_) => {}
         }
         //region MQTT loop channel handling
-        match incoming_rx.try_recv() {
-            Ok(ipcm) => match ipcm {
-                IPCMessage::Outbound(msg) => {
-                    let payload = match serde_json::to_vec(&msg.payload) {
-                        Ok(p) => p,
-                        Err(e) => {
-                            error!("Payload couldn't be serialized to vec: {e}");
-                            continue;
-                        }
-                    };
-                    match timeout(
-                        Duration::from_secs(3),
-                        mqtt.client
-                            .publish(msg.topic, QoS::AtLeastOnce, false, payload),
-                    )
-                    .await
-                    {
-                        Ok(result) => match result {
-                            Ok(_) => {
-                                if let Payload::Config(config) = msg.payload {
-                                    let vals =
-                                        config.unique_id.splitn(3, ".").collect::<Vec<&str>>();
+        // everything queued goes out each tick, otherwise a busy bridge publishes faster than this drains
+        loop {
+            match incoming_rx.try_recv() {
+                Ok(ipcm) => match ipcm {
+                    IPCMessage::Outbound(msg) => {
+                        // Payload::None is sent as a zero-length message, which clears a retained topic
+                        let payload = match msg.payload {
+                            Payload::None => vec![],
+                            Payload::Raw(ref s) => s.as_bytes().to_vec(),
+                            _ => match serde_json::to_vec(&msg.payload) {
+                                Ok(p) => p,
+                                Err(e) => {
+                                    error!("Payload couldn't be serialized to vec: {e}");
+                                    continue;
+                                }
+                            },
+                        };
+                        match timeout(
+                            Duration::from_secs(3),
+                            mqtt.client
+                                .publish(msg.topic, QoS::AtLeastOnce, msg.retain, payload),
+                        )
+                        .await
+                        {
+                            Ok(result) => match result {
+                                Ok(_) => {
+                                    if let Payload::Config(config) = msg.payload {
+                                        let vals =
+                                            config.unique_id.splitn(3, ".").collect::<Vec<&str>>();
 
-                                };
+                                    };
+                                }
+                                Err(e) => {
+                                    error!("Couldn't send message: {e}");
+                                }
+                            },
+                            Err(_e) => {
+                                error!("Timeout trying to mqtt publish!")
                             }
-                            Err(e) => {
-                                error!("Couldn't send message: {e}");
-                            }
-                        },
-                        Err(_e) => {
-                            error!("Timeout trying to mqtt publish!")
                         }
                     }
-                }
-                IPCMessage::PleaseReconnect(_, _) => {
-                    unreachable!();
-                }
-                IPCMessage::Inbound(_) => {
-                    unreachable!();
-                }
-                IPCMessage::Shutdown => {
-                    info!("MQTT Received shutdown message, exiting thread.");
-                    let _ = mqtt.client.disconnect().await;
-                    return Err(MQTTError::ExitingThread);
-                }
-                _ => {}
-            },
-            Err(e) => match e {
-                TryRecvError::Empty => {}
-                TryRecvError::Disconnected => {
-                    error!("We are disconnected!");
-                }
-            },
+                    IPCMessage::PleaseReconnect(_, _) => {
+                        unreachable!();
+                    }
+                    IPCMessage::Inbound(_) => {
+                        unreachable!();
+                    }
+                    IPCMessage::Shutdown => {
+                        info!("MQTT Received shutdown message, exiting thread.");
+                        let _ = mqtt.client.disconnect().await;
+                        return Err(MQTTError::ExitingThread);
+                    }
+                    _ => {}
+                },
+                Err(e) => match e {
+                    TryRecvError::Empty => break,
+                    TryRecvError::Disconnected => {
+                        error!("We are disconnected!");
+                        break;
+                    }
+                },
+            }
         }
 
         //endregion
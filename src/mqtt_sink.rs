@@ -0,0 +1,171 @@
+use crate::config::{AppConfig, PZEMDevice};
+use crate::consts::SINK_QUEUE_SIZE;
+use crate::detectors::detector_state_topic;
+use crate::energy_periods::period_state_topic;
+use crate::energy_tracker::CONTINUOUS_ENERGY_METRIC;
+use crate::events::publish_event;
+use crate::ipc::IPCMessage;
+use crate::payload::{
+    cost_state_topic, device_metrics, device_serial, json_state_topic, publish, publish_discovery, state_topic,
+    MeterStatePayload, Payload, PayloadValueType, StatePayload, LAST_READ_METRIC,
+};
+use crate::power_quality::power_quality_state_topic;
+use crate::readings::MeterSample;
+use crate::rules::rule_state_topic;
+use crate::sinks::{Sink, SinkMessage};
+use crate::SETTINGS;
+use futures::future::BoxFuture;
+use pzem016lib::errors::PZEMError;
+use std::collections::HashMap;
+use tokio::sync::mpsc;
+
+// what the mqtt output keeps between readings of one meter
+struct MeterOutputs {
+    device: PZEMDevice,
+    seq: u64,
+}
+
+// publishes per-meter state and Home Assistant discovery over the mqtt connection
+pub struct MqttSink {
+    config: AppConfig,
+    tx: mpsc::Sender<IPCMessage>,
+    queue_size: usize,
+    meters: HashMap<String, MeterOutputs>,
+}
+
+impl MqttSink {
+    pub fn new(config: &AppConfig, tx: mpsc::Sender<IPCMessage>) -> Self {
+        MqttSink {
+            config: config.clone(),
+            tx,
+            queue_size: config.mqtt_sink.as_ref().and_then(|s| s.queue_size).unwrap_or(SINK_QUEUE_SIZE),
+            meters: HashMap::new(),
+        }
+    }
+
+    async fn handle(&mut self, sample: &MeterSample) -> Result<(), PZEMError> {
+        let config = &self.config;
+        let tx = &self.tx;
+        let device = &sample.device;
+        let key = device.key();
+        // a meter seen for the first time, or whose settings changed, is announced afresh
+        if self.meters.get(&key).map_or(true, |m| m.device != *device) {
+            publish_discovery(device, config, tx).await?;
+            self.meters.insert(key.clone(), MeterOutputs { device: device.clone(), seq: 0 });
+        }
+        let outputs = match self.meters.get_mut(&key) {
+            Some(o) => o,
+            None => return Ok(()),
+        };
+        let serial = device_serial(device);
+        let format = config.timestamp_format();
+        // when the meter was read, which is later than now if the queue backed up
+        let read_at = format.format(sample.at.into());
+        let reading = sample.reading;
+
+        if let Some(c) = &sample.cost {
+            publish(tx, cost_state_topic(&serial), Payload::Cost(c.clone()), false).await?;
+        }
+        if config.json_state() {
+            let state_payload = MeterStatePayload {
+                volts: reading.volts,
+                amps: reading.amps,
+                watts: reading.watts,
+                watt_hours: reading.watt_hours,
+                frequency: reading.frequency,
+                power_factor: reading.power_factor,
+                apparent_power: reading.apparent_power(),
+                reactive_power: reading.reactive_power(),
+                phase_angle: reading.phase_angle(),
+                impedance: reading.impedance(),
+                load_percent: reading.load_percent(device),
+                headroom_watts: reading.headroom_watts(device),
+                seq: outputs.seq,
+                last_seen: read_at.clone(),
+            };
+            outputs.seq += 1;
+            publish(tx, json_state_topic(&serial), Payload::MeterState(state_payload), false).await?;
+        } else {
+            for m in device_metrics(device) {
+                // phase angle and impedance are undefined without load; a null would be a
+                // non-numeric state to HA, so the sensor keeps its last value until it expires
+                let value = match (m.value)(&reading, device) {
+                    Some(v) => v,
+                    None => continue,
+                };
+                let state_payload = StatePayload {
+                    value: PayloadValueType::Float(value),
+                    last_seen: read_at.clone(),
+                    ..Default::default()
+                };
+                publish(tx, state_topic(&serial, m.metric), Payload::CurrentState(state_payload), false).await?;
+            }
+            let last_read = StatePayload {
+                value: read_at.clone().into(),
+                last_seen: read_at.clone(),
+                ..Default::default()
+            };
+            publish(tx, state_topic(&serial, LAST_READ_METRIC), Payload::CurrentState(last_read), false).await?;
+        }
+        let continuous = StatePayload {
+            value: PayloadValueType::Float(sample.energy_total_wh as f32),
+            last_seen: read_at.clone(),
+            ..Default::default()
+        };
+        publish(tx, state_topic(&serial, CONTINUOUS_ENERGY_METRIC), Payload::CurrentState(continuous), false).await?;
+        let analysis = &sample.analysis;
+        for (rule, active) in analysis.rules.iter() {
+            let rule_state = StatePayload {
+                value: PayloadValueType::String(if *active { "ON" } else { "OFF" }.to_string()),
+                last_seen: read_at.clone(),
+                ..Default::default()
+            };
+            publish(tx, rule_state_topic(&serial, rule), Payload::CurrentState(rule_state), false).await?;
+        }
+        for (detector, state) in analysis.detectors.iter() {
+            publish(tx, detector_state_topic(&serial, detector), Payload::Detector(state.clone()), false).await?;
+        }
+        if let Some(pq) = &analysis.power_quality {
+            publish(tx, power_quality_state_topic(&serial), Payload::PowerQuality(pq.clone()), false).await?;
+        }
+        if let Some(p) = &analysis.periods {
+            publish(tx, period_state_topic(&serial), Payload::PeriodEnergy(p.clone()), false).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Sink for MqttSink {
+    fn name(&self) -> &'static str {
+        "mqtt"
+    }
+
+    fn queue_size(&self) -> usize {
+        self.queue_size
+    }
+
+    fn run(mut self: Box<Self>, mut rx: mpsc::Receiver<SinkMessage>) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            while let Some(msg) = rx.recv().await {
+                match msg {
+                    SinkMessage::Sample(sample) => {
+                        if let Err(e) = self.handle(&sample).await {
+                            error!("mqtt sink couldn't publish reading for {}: {e}", sample.device.key());
+                        }
+                    }
+                    SinkMessage::Event(event) => {
+                        if let Err(e) = publish_event((*event).clone(), &self.config, &self.tx).await {
+                            error!("mqtt sink couldn't publish event: {e}");
+                        }
+                    }
+                    SinkMessage::ConfigReloaded => {
+                        // discovery depends on the config, so every meter is announced again on
+                        // its next reading
+                        self.meters.clear();
+                        self.config = SETTINGS.read().await.clone();
+                    }
+                }
+            }
+        })
+    }
+}
@@ -1,22 +1,37 @@
-use crate::consts::*;
-use crate::payload;
-use pzem016lib::PZEM;
+use crate::config::{AppConfig, PZEMDevice};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::collections::{BTreeSet, HashMap};
 use pzem016lib::errors::PZEMError;
 use tokio::sync::mpsc;
-use tokio::time::sleep;
 use crate::ipc::{IPCMessage, PublishMessage};
-use crate::SHUTDOWN;
+use crate::energy_periods::{period_discovery_payloads, period_discovery_topics, PeriodEnergyPayload};
+use crate::energy_tracker::{continuous_energy_discovery, CONTINUOUS_ENERGY_METRIC};
+use crate::events::EventPayload;
+use crate::mqtt_formats::SparkplugJsonPayload;
+use crate::groups::{group_discovery_topics, remainder_discovery_topics, slug};
+use crate::tariffs::{cost_discovery_payloads, cost_discovery_topics, CostPayload};
+use crate::readings::MeterReading;
+use crate::detectors::{detector_discovery_payloads, detector_discovery_topics, DetectorStatePayload};
+use crate::power_quality::{power_quality_discovery_payloads, power_quality_discovery_topics, PowerQualityPayload};
+use crate::rules::{rule_discovery_payloads, rule_discovery_topics};
+use crate::timestamp::{Timestamp, TimestampFormat};
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct DeviceInfo {
     pub identifiers: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub connections: Vec<(String, String)>,
     pub manufacturer: String,
     pub name: String,
     pub model: String,
-    pub sw_version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sw_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suggested_area: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub via_device: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub configuration_url: Option<String>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Default, Clone)]
@@ -35,6 +50,16 @@ pub enum PayloadValueType {
 pub enum Payload {
     Config(HAConfigPayload),
     CurrentState(StatePayload),
+    MeterState(MeterStatePayload),
+    AggregateState(AggregateStatePayload),
+    PeriodEnergy(PeriodEnergyPayload),
+    Event(EventPayload),
+    Cost(CostPayload),
+    Detector(DetectorStatePayload),
+    PowerQuality(PowerQualityPayload),
+    SparkplugJson(SparkplugJsonPayload),
+    // published as-is rather than as JSON
+    Raw(String),
     #[default]
     None,
 }
@@ -75,6 +100,14 @@ pub struct HAConfigPayload {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub value_template: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub json_attributes_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_reset_value_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub availability_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub availability_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub suggested_display_precision: Option<u8>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub assumed_state: Option<bool>,
@@ -115,14 +148,23 @@ pub struct StatePayload {
     pub description: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub notes: Option<String>,
-    pub last_seen: SystemTime,
+    pub last_seen: Timestamp,
+}
+
+impl From<Timestamp> for PayloadValueType {
+    fn from(t: Timestamp) -> Self {
+        match t {
+            Timestamp::Text(s) => PayloadValueType::String(s),
+            Timestamp::Epoch(n) => PayloadValueType::Int(n as i64),
+        }
+    }
 }
 
 impl Default for StatePayload {
     fn default() -> Self {
         StatePayload {
             value: PayloadValueType::None,
-            last_seen: SystemTime::now(),
+            last_seen: TimestampFormat::default().now(),
             description: None,
             label: None,
             notes: None,
@@ -130,6 +172,54 @@ impl Default for StatePayload {
     }
 }
 
+// every field of one reading, published as a single document so consumers never mix
+// values from different polls
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MeterStatePayload {
+    pub volts: f32,
+    pub amps: f32,
+    pub watts: f32,
+    pub watt_hours: f32,
+    pub frequency: f32,
+    pub power_factor: f32,
+    pub apparent_power: f32,
+    pub reactive_power: f32,
+    // undefined without load, so left out rather than sent as a non-numeric null
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phase_angle: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub impedance: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub load_percent: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub headroom_watts: Option<f32>,
+    pub seq: u64,
+    pub last_seen: Timestamp,
+}
+
+// state of a virtual meter computed from other meters' readings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AggregateStatePayload {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub volts: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amps: Option<f32>,
+    pub watts: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub watt_hours: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency: Option<f32>,
+    pub members: usize,
+    pub members_reporting: usize,
+    pub stale: bool,
+    // only set for remainder meters: the unclamped result was negative
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ct_error: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unclamped_watts: Option<f32>,
+    pub last_seen: Timestamp,
+}
+
 #[derive(Debug, Clone)]
 pub struct CompoundPayload {
     pub(crate) config: HAConfigPayload,
@@ -138,199 +228,301 @@ pub struct CompoundPayload {
     pub(crate) state_topic: String,
 }
 
-pub async fn generate_payloads(pzem: &mut PZEM, tx: tokio::sync::mpsc::Sender<IPCMessage>) -> Result<(),PZEMError>{
-    loop {
-        if let Some(shutting_down) = SHUTDOWN.get() {
-                    return Err(PZEMError::ExitingThread);
-                }
-        for unit_id in 101..=144 {
-            let model: String = format!("pzem016");
-            let serial: String = format!("{unit_id}");
-            let device_info = payload::DeviceInfo {
-                identifiers: vec![serial.clone()],
-                manufacturer: "Peacefair".to_string(),
-                name: "PZEM-016".to_string(),
-                model: model.clone(),
-                sw_version: "".to_string(),
-            };
-            let unit_name = format!("{model}-{serial}");
-            let mut config_payload: HAConfigPayload = HAConfigPayload::default();
-            let mut state_payload: StatePayload = StatePayload::default();
-
-            let data = match pzem.get_data(unit_id).await {
-                Ok(d) => d,
-                Err(e) => {
-                    warn!("couldn't read data for {unit_id}: {e}");
-                    continue;
-                }
-            };
-
-            //volts
-            let config_topic: String = format!("homeassistant/sensor/pzem016-{serial}/volts/config");
-            let state_topic = format!("pzem016mqtt/pzem016-{serial}/volts/value");
-            config_payload.state_topic = state_topic.clone();
-            config_payload.name = format!("{}-voltage", unit_name.clone());
-            config_payload.device_class = Some("voltage".to_string());
-            config_payload.state_class = Some("measurement".to_string());
-            config_payload.expires_after = 300;
-            config_payload.value_template = Some("{{ value_json.value }}".to_string());
-            config_payload.unique_id = config_payload.name.clone();
-            config_payload.suggested_display_precision = Some(1);
-            config_payload.native_uom = Some("V".to_string());
-            config_payload.device = device_info.clone();
-            state_payload.value = PayloadValueType::Float(data.volts as f32);
-            if let Err(e) = tx.send(IPCMessage::Outbound(PublishMessage {
-                topic: config_topic,
-                payload: Payload::Config(config_payload.clone())
-            })).await {
-                return Err(PZEMError::Misc(format!("Couldn't publish payload to ipc bus: {e}")));
-            }
-            if let Err(e) = tx.send(IPCMessage::Outbound(PublishMessage {
-                topic: state_topic,
-                payload: Payload::CurrentState(state_payload.clone())
-            })).await {
-                return Err(PZEMError::Misc(format!("Couldn't publish payload to ipc bus: {e}")));
-            }
+pub struct MetricSpec {
+    pub metric: &'static str,
+    field: &'static str,
+    pub name: &'static str,
+    device_class: Option<&'static str>,
+    state_class: &'static str,
+    pub uom: Option<&'static str>,
+    pub precision: u8,
+    pub value: fn(&MeterReading, &PZEMDevice) -> Option<f32>,
+}
 
-            //current
-            let config_topic: String = format!("homeassistant/sensor/pzem016-{serial}/current/config");
-            let state_topic = format!("pzem016mqtt/pzem016-{serial}/current/value");
-            config_payload.state_topic = state_topic.clone();
-            config_payload.name = format!("{}-current", unit_name.clone());
-            config_payload.device_class = Some("current".to_string());
-            config_payload.state_class = Some("measurement".to_string());
-            config_payload.expires_after = 300;
-            config_payload.value_template = Some("{{ value_json.value }}".to_string());
-            config_payload.unique_id = config_payload.name.clone();
-            config_payload.suggested_display_precision = Some(1);
-            config_payload.native_uom = Some("A".to_string());
-            config_payload.device = device_info.clone();
-            state_payload.value = PayloadValueType::Float(data.amps as f32);
-            if let Err(e) = tx.send(IPCMessage::Outbound(PublishMessage {
-                topic: config_topic,
-                payload: Payload::Config(config_payload.clone())
-            })).await {
-                return Err(PZEMError::Misc(format!("Couldn't publish payload to ipc bus: {e}")));
-            }
-            if let Err(e) = tx.send(IPCMessage::Outbound(PublishMessage {
-                topic: state_topic,
-                payload: Payload::CurrentState(state_payload.clone())
-            })).await {
-                return Err(PZEMError::Misc(format!("Couldn't publish payload to ipc bus: {e}")));
-            }
+const METRICS: [MetricSpec; 10] = [
+    MetricSpec { metric: "volts", field: "volts", name: "voltage", device_class: Some("voltage"), state_class: "measurement", uom: Some("V"), precision: 1, value: |r, _| Some(r.volts) },
+    MetricSpec { metric: "current", field: "amps", name: "current", device_class: Some("current"), state_class: "measurement", uom: Some("A"), precision: 1, value: |r, _| Some(r.amps) },
+    MetricSpec { metric: "power", field: "watts", name: "power", device_class: Some("power"), state_class: "measurement", uom: Some("W"), precision: 1, value: |r, _| Some(r.watts) },
+    // the raw register drops when it wraps or is cleared, so only energy_continuous is a total to HA
+    MetricSpec { metric: "energy", field: "watt_hours", name: "energy", device_class: None, state_class: "measurement", uom: Some("Wh"), precision: 1, value: |r, _| Some(r.watt_hours) },
+    MetricSpec { metric: "frequency", field: "frequency", name: "frequency", device_class: Some("frequency"), state_class: "measurement", uom: Some("Hz"), precision: 1, value: |r, _| Some(r.frequency) },
+    MetricSpec { metric: "power_factor", field: "power_factor", name: "power_factor", device_class: Some("power_factor"), state_class: "measurement", uom: None, precision: 0, value: |r, _| Some(r.power_factor) },
+    MetricSpec { metric: "apparent_power", field: "apparent_power", name: "apparent_power", device_class: Some("apparent_power"), state_class: "measurement", uom: Some("VA"), precision: 1, value: |r, _| Some(r.apparent_power()) },
+    MetricSpec { metric: "reactive_power", field: "reactive_power", name: "reactive_power", device_class: Some("reactive_power"), state_class: "measurement", uom: Some("var"), precision: 1, value: |r, _| Some(r.reactive_power()) },
+    MetricSpec { metric: "phase_angle", field: "phase_angle", name: "phase_angle", device_class: None, state_class: "measurement", uom: Some("°"), precision: 1, value: |r, _| r.phase_angle() },
+    MetricSpec { metric: "impedance", field: "impedance", name: "impedance", device_class: None, state_class: "measurement", uom: Some("Ω"), precision: 1, value: |r, _| r.impedance() },
+];
 
-            //power
-            let config_topic: String = format!("homeassistant/sensor/pzem016-{serial}/power/config");
-            let state_topic = format!("pzem016mqtt/pzem016-{serial}/power/value");
-            config_payload.state_topic = state_topic.clone();
-            config_payload.name = format!("{}-power", unit_name.clone());
-            config_payload.device_class = Some("power".to_string());
-            config_payload.state_class = Some("measurement".to_string());
-            config_payload.expires_after = 300;
-            config_payload.value_template = Some("{{ value_json.value }}".to_string());
-            config_payload.unique_id = config_payload.name.clone();
-            config_payload.suggested_display_precision = Some(1);
-            config_payload.native_uom = Some("W".to_string());
-            config_payload.device = device_info.clone();
-            state_payload.value = PayloadValueType::Float(data.watts as f32);
-            if let Err(e) = tx.send(IPCMessage::Outbound(PublishMessage {
-                topic: config_topic,
-                payload: Payload::Config(config_payload.clone())
-            })).await {
-                return Err(PZEMError::Misc(format!("Couldn't publish payload to ipc bus: {e}")));
-            }
-            if let Err(e) = tx.send(IPCMessage::Outbound(PublishMessage {
-                topic: state_topic,
-                payload: Payload::CurrentState(state_payload.clone())
-            })).await {
-                return Err(PZEMError::Misc(format!("Couldn't publish payload to ipc bus: {e}")));
-            }
+// only announced for devices with a rating_amps
+const RATING_METRICS: [MetricSpec; 2] = [
+    MetricSpec { metric: "load_percent", field: "load_percent", name: "load_percent", device_class: None, state_class: "measurement", uom: Some("%"), precision: 0, value: |r, d| r.load_percent(d) },
+    MetricSpec { metric: "headroom", field: "headroom_watts", name: "headroom", device_class: Some("power"), state_class: "measurement", uom: Some("W"), precision: 0, value: |r, d| r.headroom_watts(d) },
+];
 
-            //energy
-            let config_topic: String = format!("homeassistant/sensor/pzem016-{serial}/energy/config");
-            let state_topic = format!("pzem016mqtt/pzem016-{serial}/energy/value");
-            config_payload.state_topic = state_topic.clone();
-            config_payload.name = format!("{}-energy", unit_name.clone());
-            config_payload.device_class = Some("energy".to_string());
-            config_payload.state_class = Some("total_increasing".to_string());
-            config_payload.expires_after = 300;
-            config_payload.value_template = Some("{{ value_json.value }}".to_string());
-            config_payload.unique_id = config_payload.name.clone();
-            config_payload.suggested_display_precision = Some(1);
-            config_payload.native_uom = Some("Wh".to_string());
-            config_payload.device = device_info.clone();
-            state_payload.value = PayloadValueType::Float(data.watt_hours as f32);
-            if let Err(e) = tx.send(IPCMessage::Outbound(PublishMessage {
-                topic: config_topic,
-                payload: Payload::Config(config_payload.clone())
-            })).await {
-                return Err(PZEMError::Misc(format!("Couldn't publish payload to ipc bus: {e}")));
-            }
-            if let Err(e) = tx.send(IPCMessage::Outbound(PublishMessage {
-                topic: state_topic,
-                payload: Payload::CurrentState(state_payload.clone())
-            })).await {
-                return Err(PZEMError::Misc(format!("Couldn't publish payload to ipc bus: {e}")));
-            }
+pub const LAST_READ_METRIC: &str = "last_read";
 
-            // frequency
-            let config_topic: String =
-                format!("homeassistant/sensor/pzem016-{serial}/frequency/config");
-            let state_topic = format!("pzem016mqtt/pzem016-{serial}/frequency/value");
-            config_payload.state_topic = state_topic.clone();
-            config_payload.name = format!("{}-frequency", unit_name.clone());
-            config_payload.device_class = Some("frequency".to_string());
-            config_payload.state_class = Some("measurement".to_string());
-            config_payload.expires_after = 300;
-            config_payload.value_template = Some("{{ value_json.value }}".to_string());
-            config_payload.unique_id = config_payload.name.clone();
-            config_payload.suggested_display_precision = Some(1);
-            config_payload.native_uom = Some("Hz".to_string());
-            config_payload.device = device_info.clone();
-            state_payload.value = PayloadValueType::Float(data.frequency as f32);
-            if let Err(e) = tx.send(IPCMessage::Outbound(PublishMessage {
-                topic: config_topic,
-                payload: Payload::Config(config_payload.clone())
-            })).await {
-                return Err(PZEMError::Misc(format!("Couldn't publish payload to ipc bus: {e}")));
-            }
-            if let Err(e) = tx.send(IPCMessage::Outbound(PublishMessage {
-                topic: state_topic,
-                payload: Payload::CurrentState(state_payload.clone())
-            })).await {
-                return Err(PZEMError::Misc(format!("Couldn't publish payload to ipc bus: {e}")));
-            }
+pub fn device_metrics(device: &PZEMDevice) -> impl Iterator<Item = &'static MetricSpec> {
+    let rated = device.rating_amps.is_some();
+    METRICS.iter().chain(RATING_METRICS.iter().filter(move |_| rated))
+}
+
+// modbus addresses repeat across ports, so the port is part of the serial, e.g. dev_ttyusb0-1
+pub fn device_serial(device: &PZEMDevice) -> String {
+    let port: Vec<String> = slug(&device.port).split('_').filter(|p| !p.is_empty()).map(|p| p.to_string()).collect();
+    format!("{}-{}", port.join("_"), device.addr)
+}
+
+// the serial before it included the port
+fn legacy_device_serial(device: &PZEMDevice) -> String {
+    format!("{}", device.addr)
+}
+
+pub fn config_topic(serial: &str, metric: &str) -> String {
+    format!("homeassistant/sensor/pzem016-{serial}/{metric}/config")
+}
+
+pub fn state_topic(serial: &str, metric: &str) -> String {
+    format!("pzem016mqtt/pzem016-{serial}/{metric}/value")
+}
+
+pub fn cost_state_topic(serial: &str) -> String {
+    format!("pzem016mqtt/pzem016-{serial}/cost")
+}
+
+pub fn json_state_topic(serial: &str) -> String {
+    format!("pzem016mqtt/pzem016-{serial}/state")
+}
+
+pub fn discovery_topics(device: &PZEMDevice, config: &AppConfig) -> Vec<String> {
+    serial_discovery_topics(&device_serial(device), device, config)
+}
+
+fn serial_discovery_topics(serial: &str, device: &PZEMDevice, config: &AppConfig) -> Vec<String> {
+    let mut topics: Vec<String> = device_metrics(device).map(|m| config_topic(serial, m.metric)).collect();
+    topics.push(config_topic(serial, LAST_READ_METRIC));
+    topics.push(config_topic(serial, CONTINUOUS_ENERGY_METRIC));
+    if config.energy_periods.is_some() {
+        topics.extend(period_discovery_topics(serial));
+    }
+    if config.tariffs.is_some() {
+        topics.extend(cost_discovery_topics(serial));
+    }
+    topics.extend(rule_discovery_topics(serial, device, config));
+    topics.extend(detector_discovery_topics(serial, device));
+    if let Some(pq) = &config.power_quality {
+        topics.extend(power_quality_discovery_topics(serial, pq));
+    }
+    topics
+}
+
+pub fn expected_discovery_topics(config: &AppConfig) -> BTreeSet<String> {
+    let mut topics: BTreeSet<String> = BTreeSet::new();
+    if config.mqtt_sink_enabled() {
+        topics.extend(config.devices().iter().flat_map(|d| discovery_topics(d, config)));
+    }
+    topics.insert(bridge_config_topic(config));
+    topics.extend(config.groups().iter().flat_map(|g| group_discovery_topics(g, config)));
+    topics.extend(config.remainders().iter().flat_map(remainder_discovery_topics));
+    topics
+}
 
-            // power_factor
-            let config_topic: String =
-                format!("homeassistant/sensor/pzem016-{serial}/power_factor/config");
-            let state_topic = format!("pzem016mqtt/pzem016-{serial}/power_factor/value");
-            config_payload.state_topic = state_topic.clone();
-            config_payload.name = format!("{}-power_factor", unit_name.clone());
-            config_payload.device_class = Some("power_factor".to_string());
-            config_payload.state_class = Some("measurement".to_string());
-            config_payload.expires_after = 300;
-            config_payload.value_template = Some("{{ value_json.value }}".to_string());
-            config_payload.unique_id = config_payload.name.clone();
-            config_payload.suggested_display_precision = Some(0);
-            config_payload.native_uom = None;
-            config_payload.device = device_info.clone();
-            state_payload.value = PayloadValueType::Float(data.power_factor);
-            if let Err(e) = tx.send(IPCMessage::Outbound(PublishMessage {
-                topic: config_topic,
-                payload: Payload::Config(config_payload.clone())
-            })).await {
-                return Err(PZEMError::Misc(format!("Couldn't publish payload to ipc bus: {e}")));
+// what earlier versions announced for the configured meters, so the registry can clear them
+// even if they were published before it existed
+pub fn legacy_discovery_topics(config: &AppConfig) -> BTreeSet<String> {
+    config
+        .devices()
+        .iter()
+        .flat_map(|d| serial_discovery_topics(&legacy_device_serial(d), d, config))
+        .collect()
+}
+
+pub fn bridge_identifier(config: &AppConfig) -> String {
+    config.client_id()
+}
+
+fn bridge_config_topic(config: &AppConfig) -> String {
+    format!("homeassistant/binary_sensor/{}/status/config", bridge_identifier(config))
+}
+
+pub fn bridge_status_topic(config: &AppConfig) -> String {
+    format!("pzem016mqtt/{}/status", bridge_identifier(config))
+}
+
+// the bridge itself is a device in HA so that every meter can hang off it via `via_device`
+fn bridge_device_info(config: &AppConfig) -> DeviceInfo {
+    DeviceInfo {
+        identifiers: vec![bridge_identifier(config)],
+        manufacturer: "pzem016mqtt".to_string(),
+        name: bridge_identifier(config),
+        model: "pzem016mqtt bridge".to_string(),
+        sw_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+        configuration_url: config.http_public_url.as_ref().map(|u| format!("{}/health", u.trim_end_matches('/'))),
+        ..Default::default()
+    }
+}
+
+fn meter_device_info(device: &PZEMDevice, config: &AppConfig) -> DeviceInfo {
+    DeviceInfo {
+        identifiers: vec![format!("pzem016-{}-{}", device.port, device.addr)],
+        connections: vec![("modbus".to_string(), device.key())],
+        manufacturer: "Peacefair".to_string(),
+        name: device.breaker.clone(),
+        model: "PZEM-016".to_string(),
+        sw_version: None,
+        suggested_area: device.area.clone(),
+        via_device: Some(bridge_identifier(config)),
+        configuration_url: None,
+    }
+}
+
+pub fn bridge_state(online: bool, format: TimestampFormat) -> StatePayload {
+    StatePayload {
+        value: PayloadValueType::String(if online { "online" } else { "offline" }.to_string()),
+        last_seen: format.now(),
+        ..Default::default()
+    }
+}
+
+pub async fn publish_bridge_discovery(config: &AppConfig, tx: &mpsc::Sender<IPCMessage>) -> Result<(), PZEMError> {
+    let name = format!("{}-status", bridge_identifier(config));
+    let config_payload = HAConfigPayload {
+        name: name.clone(),
+        unique_id: name,
+        device: bridge_device_info(config),
+        state_topic: bridge_status_topic(config),
+        device_class: Some("connectivity".to_string()),
+        entity_category: Some(EntityCategory::Diagnostic),
+        value_template: Some("{{ value_json.value }}".to_string()),
+        payload_on: Some("online".to_string()),
+        payload_off: Some("offline".to_string()),
+        ..Default::default()
+    };
+    publish(tx, bridge_config_topic(config), Payload::Config(config_payload), true).await?;
+    publish(tx, bridge_status_topic(config), Payload::CurrentState(bridge_state(true, config.timestamp_format())), true).await
+}
+
+fn metric_config(m: &MetricSpec, unit_name: &str, device_info: &DeviceInfo) -> HAConfigPayload {
+    let mut config_payload: HAConfigPayload = HAConfigPayload::default();
+    config_payload.name = format!("{}-{}", unit_name, m.name);
+    config_payload.device_class = m.device_class.map(|d| d.to_string());
+    config_payload.state_class = Some(m.state_class.to_string());
+    config_payload.expires_after = 300;
+    config_payload.unique_id = config_payload.name.clone();
+    config_payload.suggested_display_precision = Some(m.precision);
+    config_payload.native_uom = m.uom.map(|u| u.to_string());
+    config_payload.device = device_info.clone();
+    config_payload
+}
+
+// a field that's missing from the state document leaves the sensor at its last value
+fn json_field_template(field: &str) -> String {
+    format!("{{{{ value_json.{field} if value_json.{field} is defined else this.state }}}}")
+}
+
+// discovery for the METRICS whose json field is in `fields`, all read from one JSON state topic;
+// used by the virtual meters, which always publish a single state document
+pub fn json_metric_configs(
+    serial: &str,
+    unit_name: &str,
+    fields: &[&str],
+    state_topic: &str,
+    device_info: &DeviceInfo,
+) -> Vec<(String, HAConfigPayload)> {
+    METRICS
+        .iter()
+        .filter(|m| fields.contains(&m.field))
+        .map(|m| {
+            let mut config_payload = metric_config(m, unit_name, device_info);
+            config_payload.state_topic = state_topic.to_string();
+            config_payload.value_template = Some(json_field_template(m.field));
+            config_payload.json_attributes_topic = Some(state_topic.to_string());
+            // virtual meters' energy is built from continuous counters, so it is a total
+            if m.field == "watt_hours" {
+                config_payload.device_class = Some("energy".to_string());
+                config_payload.state_class = Some("total_increasing".to_string());
             }
-            if let Err(e) = tx.send(IPCMessage::Outbound(PublishMessage {
-                topic: state_topic,
-                payload: Payload::CurrentState(state_payload.clone())
-            })).await {
-                return Err(PZEMError::Misc(format!("Couldn't publish payload to ipc bus: {e}")));
+            (config_topic(serial, m.metric), config_payload)
+        })
+        .collect()
+}
+
+pub fn json_metric_topics(serial: &str, fields: &[&str]) -> Vec<String> {
+    METRICS
+        .iter()
+        .filter(|m| fields.contains(&m.field))
+        .map(|m| config_topic(serial, m.metric))
+        .collect()
+}
+
+fn discovery_payloads(device: &PZEMDevice, config: &AppConfig) -> Vec<(String, HAConfigPayload)> {
+    let model: String = "pzem016".to_string();
+    let serial = device_serial(device);
+    let device_info = meter_device_info(device, config);
+    let unit_name = format!("{model}-{serial}");
+    let mut payloads: Vec<(String, HAConfigPayload)> = device_metrics(device)
+        .map(|m| {
+            let mut config_payload = metric_config(m, &unit_name, &device_info);
+            if config.json_state() {
+                config_payload.state_topic = json_state_topic(&serial);
+                config_payload.value_template = Some(json_field_template(m.field));
+                config_payload.json_attributes_topic = Some(json_state_topic(&serial));
+            } else {
+                config_payload.state_topic = state_topic(&serial, m.metric);
+                config_payload.value_template = Some("{{ value_json.value }}".to_string());
             }
-        }
-        let _ = sleep(Duration::from_millis(MQTT_POLL_INTERVAL_MILLIS)).await;
+            (config_topic(&serial, m.metric), config_payload)
+        })
+        .collect();
+
+    let format = config.timestamp_format();
+    let mut last_read: HAConfigPayload = HAConfigPayload::default();
+    if config.json_state() {
+        last_read.state_topic = json_state_topic(&serial);
+        last_read.value_template = Some(format.ha_template("value_json.last_seen"));
+    } else {
+        last_read.state_topic = state_topic(&serial, LAST_READ_METRIC);
+        last_read.value_template = Some(format.ha_template("value_json.value"));
+    }
+    last_read.name = format!("{unit_name}-{LAST_READ_METRIC}");
+    last_read.unique_id = last_read.name.clone();
+    last_read.device_class = Some("timestamp".to_string());
+    last_read.entity_category = Some(EntityCategory::Diagnostic);
+    last_read.device = device_info.clone();
+    payloads.push((config_topic(&serial, LAST_READ_METRIC), last_read));
+    payloads.push(continuous_energy_discovery(&serial, &unit_name, state_topic(&serial, CONTINUOUS_ENERGY_METRIC), &device_info));
+    if config.energy_periods.is_some() {
+        payloads.extend(period_discovery_payloads(&serial, &unit_name, &device_info));
     }
+    if let Some(tariffs) = &config.tariffs {
+        payloads.extend(cost_discovery_payloads(&serial, &unit_name, &cost_state_topic(&serial), &tariffs.currency, &device_info));
+    }
+    payloads.extend(rule_discovery_payloads(&serial, &unit_name, device, config, &device_info));
+    payloads.extend(detector_discovery_payloads(&serial, &unit_name, device, &device_info));
+    if let Some(pq) = &config.power_quality {
+        payloads.extend(power_quality_discovery_payloads(&serial, &unit_name, pq, &device_info));
+    }
+    payloads
+}
 
+pub async fn publish(
+    tx: &mpsc::Sender<IPCMessage>,
+    topic: String,
+    payload: Payload,
+    retain: bool,
+) -> Result<(), PZEMError> {
+    if let Err(e) = tx.send(IPCMessage::Outbound(PublishMessage {
+        topic,
+        payload,
+        retain,
+    })).await {
+        return Err(PZEMError::Misc(format!("Couldn't publish payload to ipc bus: {e}")));
+    }
+    Ok(())
+}
 
+pub async fn publish_discovery(device: &PZEMDevice, config: &AppConfig, tx: &mpsc::Sender<IPCMessage>) -> Result<(), PZEMError> {
+    for (topic, config_payload) in discovery_payloads(device, config) {
+        publish(tx, topic, Payload::Config(config_payload), true).await?;
+    }
+    Ok(())
 }
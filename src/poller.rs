@@ -0,0 +1,132 @@
+use crate::analysis::MeterAnalysis;
+use crate::config::PZEMDevice;
+use crate::consts::{METER_OFFLINE_AFTER_FAILURES, POLL_TIME};
+use crate::energy_tracker::{EnergyTracker, ENERGY_RESET_REQUESTS};
+use crate::events::{BridgeEvent, EnergyResetKind};
+use crate::ipc::IPCMessage;
+use crate::readings::{CachedReading, MeterReading, MeterSample, LATEST_READINGS};
+use crate::tariffs::{CostCounters, TariffSchedule};
+use crate::{SETTINGS, SHUTDOWN};
+use chrono::Utc;
+use pzem016lib::errors::PZEMError;
+use pzem016lib::PZEM;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::sleep;
+
+// one modbus connection, shared by the pollers of every meter on that port
+pub type SharedPort = Arc<Mutex<PZEM>>;
+
+// reads one meter every POLL_TIME, analyses the reading and hands it to the sinks along with
+// any events it raised; the sinks only format and deliver
+pub async fn poll_meter(pzem: SharedPort, device: PZEMDevice, tx: mpsc::Sender<IPCMessage>) -> Result<(), PZEMError> {
+    let config = SETTINGS.read().await.clone();
+    let mut energy = EnergyTracker::load(config.state_dir(), &device.key());
+    let mut analysis = MeterAnalysis::new(&device, &config);
+    let tariffs = match config.tariffs.as_ref().map(|t| TariffSchedule::new(t, &config)) {
+        Some(Ok(t)) => Some(t),
+        Some(Err(e)) => {
+            error!("cost tracking disabled for {}: {e}", device.key());
+            None
+        }
+        None => None,
+    };
+    let mut cost = CostCounters::load(config.state_dir(), &device.key());
+    let mut previous_raw_wh = energy.last_raw_wh;
+    let mut failed_reads: u32 = 0;
+    loop {
+        if SHUTDOWN.get().is_some() {
+            return Err(PZEMError::ExitingThread);
+        }
+        // the lock is held for the whole request and response, so meters on the same bus take turns
+        let result = pzem.lock().await.get_data(device.addr).await;
+        match result {
+            Ok(data) => {
+                if failed_reads >= METER_OFFLINE_AFTER_FAILURES {
+                    let event = BridgeEvent::MeterOnline {
+                        meter: device.key(),
+                        breaker: device.breaker.clone(),
+                    };
+                    raise(&tx, event).await;
+                }
+                failed_reads = 0;
+                let reading = MeterReading {
+                    volts: data.volts as f32,
+                    amps: data.amps as f32,
+                    watts: data.watts as f32,
+                    watt_hours: data.watt_hours as f32,
+                    frequency: data.frequency as f32,
+                    power_factor: data.power_factor,
+                };
+                let mut energy_reset = energy
+                    .update(reading.watt_hours, config.energy_wrap_wh())
+                    .map(|kind| (kind, previous_raw_wh.unwrap_or_default()));
+                // for a requested reset the event carries the total that was cleared
+                if ENERGY_RESET_REQUESTS.write().await.remove(&device.key()) {
+                    let previous_total = energy.clear();
+                    info!("energy total of {} reset from {previous_total}Wh on request", device.key());
+                    energy_reset = Some((EnergyResetKind::Requested, previous_total as f32));
+                }
+                previous_raw_wh = Some(reading.watt_hours);
+                let now = Utc::now();
+                let cost_payload = tariffs.as_ref().map(|schedule| {
+                    cost.update(energy.continuous_wh, now, schedule);
+                    let (fixed_today, fixed_month) = schedule.fixed_charges(&device.breaker, now);
+                    schedule.payload(cost.today + fixed_today, cost.month + fixed_month, reading.watts, now)
+                });
+                LATEST_READINGS.write().await.insert(device.key(), CachedReading {
+                    reading,
+                    energy_wh: energy.continuous_wh,
+                    cost_today: cost_payload.as_ref().map(|c| c.today),
+                    cost_month: cost_payload.as_ref().map(|c| c.month),
+                    at: Instant::now(),
+                });
+                let (results, mut events) =
+                    analysis.update(&reading, energy.continuous_wh, now, config.timestamp_format().format(now.into()));
+                if let Some((kind, previous_wh)) = energy_reset {
+                    events.insert(0, BridgeEvent::EnergyReset {
+                        meter: device.key(),
+                        breaker: device.breaker.clone(),
+                        kind,
+                        previous_wh,
+                        current_wh: reading.watt_hours,
+                    });
+                }
+                let sample = MeterSample {
+                    device: device.clone(),
+                    at: now,
+                    reading,
+                    energy_wh: energy.continuous_wh,
+                    energy_total_wh: energy.total_wh(),
+                    cost: cost_payload,
+                    analysis: results,
+                };
+                if let Err(e) = tx.send(IPCMessage::Reading(sample)).await {
+                    return Err(PZEMError::Misc(format!("Couldn't send reading to ipc bus: {e}")));
+                }
+                for event in events {
+                    raise(&tx, event).await;
+                }
+            }
+            Err(e) => {
+                warn!("couldn't read data for {} ({}): {e}", device.addr, device.breaker);
+                failed_reads += 1;
+                if failed_reads == METER_OFFLINE_AFTER_FAILURES {
+                    let event = BridgeEvent::MeterOffline {
+                        meter: device.key(),
+                        breaker: device.breaker.clone(),
+                        failed_reads,
+                    };
+                    raise(&tx, event).await;
+                }
+            }
+        };
+        let _ = sleep(Duration::from_secs(POLL_TIME as u64)).await;
+    }
+}
+
+async fn raise(tx: &mpsc::Sender<IPCMessage>, event: BridgeEvent) {
+    info!("event: {:?}", event);
+    let _ = tx.send(IPCMessage::Event(event)).await;
+}
@@ -0,0 +1,225 @@
+use crate::config::PowerQuality;
+use crate::groups::slug;
+use crate::payload::{config_topic, DeviceInfo, EntityCategory, HAConfigPayload};
+use crate::state::{load_json, save_json};
+use crate::timestamp::Timestamp;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+const QUANTITIES: [(&str, &str, &str); 2] = [("volts", "voltage", "V"), ("frequency", "frequency", "Hz")];
+const STATS: [&str; 4] = ["min", "max", "mean", "stddev"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct Stats {
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    pub stddev: f32,
+    pub samples: usize,
+}
+
+impl Stats {
+    fn of(values: impl Iterator<Item = f32> + Clone) -> Option<Self> {
+        let n = values.clone().count();
+        if n == 0 {
+            return None;
+        }
+        let mean = values.clone().sum::<f32>() / n as f32;
+        let variance = values.clone().map(|v| (v - mean) * (v - mean)).sum::<f32>() / n as f32;
+        Some(Stats {
+            min: values.clone().fold(f32::MAX, f32::min),
+            max: values.fold(f32::MIN, f32::max),
+            mean,
+            stddev: variance.sqrt(),
+            samples: n,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct WindowStats {
+    pub volts: Stats,
+    pub frequency: Stats,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PowerQualityPayload {
+    // keyed by window label, e.g. 1m, 15m
+    pub windows: BTreeMap<String, WindowStats>,
+    pub sags: u64,
+    pub swells: u64,
+    pub last_seen: Timestamp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Excursion {
+    Normal,
+    Sag,
+    Swell,
+}
+
+// sag and swell counts, kept across restarts like the energy counters
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct ExcursionCounters {
+    sags: u64,
+    swells: u64,
+}
+
+// rolling voltage and frequency statistics for one meter
+pub struct PowerQualityTracker {
+    settings: PowerQuality,
+    windows: Vec<u64>,
+    samples: VecDeque<(Instant, f32, f32)>,
+    excursion: Excursion,
+    counters: ExcursionCounters,
+    path: PathBuf,
+}
+
+pub fn window_label(secs: u64) -> String {
+    if secs % 60 == 0 {
+        format!("{}m", secs / 60)
+    } else {
+        format!("{secs}s")
+    }
+}
+
+impl PowerQualityTracker {
+    pub fn new(settings: &PowerQuality, state_dir: &str, meter_key: &str) -> Self {
+        let path = Path::new(state_dir).join("power_quality").join(format!("{}.json", slug(meter_key)));
+        PowerQualityTracker {
+            settings: settings.clone(),
+            windows: settings.windows_secs(),
+            samples: VecDeque::new(),
+            excursion: Excursion::Normal,
+            counters: load_json::<ExcursionCounters>(&path).unwrap_or_default(),
+            path,
+        }
+    }
+
+    pub fn update(&mut self, volts: f32, frequency: f32, now: Instant) {
+        self.samples.push_back((now, volts, frequency));
+        let longest = self.windows.iter().copied().max().unwrap_or_default();
+        while self.samples.front().is_some_and(|(t, _, _)| now.duration_since(*t).as_secs() >= longest) {
+            self.samples.pop_front();
+        }
+
+        // each excursion is counted once, when the voltage first crosses the threshold, and
+        // lasts until it is back inside it by rearm_volts so noise on the threshold isn't counted
+        let rearm = self.settings.rearm_volts();
+        let sag = self.settings.sag_below_volts;
+        let swell = self.settings.swell_above_volts;
+        let excursion = match self.excursion {
+            Excursion::Sag if sag.is_some_and(|v| volts < v + rearm) => Excursion::Sag,
+            Excursion::Swell if swell.is_some_and(|v| volts > v - rearm) => Excursion::Swell,
+            _ if sag.is_some_and(|v| volts < v) => Excursion::Sag,
+            _ if swell.is_some_and(|v| volts > v) => Excursion::Swell,
+            _ => Excursion::Normal,
+        };
+        if excursion != self.excursion {
+            match excursion {
+                Excursion::Sag => self.counters.sags += 1,
+                Excursion::Swell => self.counters.swells += 1,
+                Excursion::Normal => {}
+            }
+            if excursion != Excursion::Normal {
+                save_json(&self.path, &self.counters);
+            }
+            self.excursion = excursion;
+        }
+    }
+
+    pub fn payload(&self, now: Instant, last_seen: Timestamp) -> PowerQualityPayload {
+        let mut windows: BTreeMap<String, WindowStats> = BTreeMap::new();
+        for secs in self.windows.iter() {
+            let in_window = self.samples.iter().filter(|(t, _, _)| now.duration_since(*t).as_secs() < *secs);
+            if let (Some(volts), Some(frequency)) = (
+                Stats::of(in_window.clone().map(|s| s.1)),
+                Stats::of(in_window.map(|s| s.2)),
+            ) {
+                windows.insert(window_label(*secs), WindowStats { volts, frequency });
+            }
+        }
+        PowerQualityPayload {
+            windows,
+            sags: self.counters.sags,
+            swells: self.counters.swells,
+            last_seen,
+        }
+    }
+}
+
+pub fn power_quality_state_topic(serial: &str) -> String {
+    format!("pzem016mqtt/pzem016-{serial}/power_quality")
+}
+
+fn metric_names(settings: &PowerQuality) -> Vec<(String, String, Option<&'static str>, &'static str)> {
+    // (metric, value path, unit, quantity)
+    let mut metrics = vec![];
+    for secs in settings.windows_secs() {
+        let label = window_label(secs);
+        for (field, quantity, uom) in QUANTITIES {
+            for stat in STATS {
+                metrics.push((
+                    format!("{field}_{label}_{stat}"),
+                    format!("value_json.windows['{label}'].{field}.{stat}"),
+                    Some(uom),
+                    quantity,
+                ));
+            }
+        }
+    }
+    metrics
+}
+
+pub fn power_quality_discovery_topics(serial: &str, settings: &PowerQuality) -> Vec<String> {
+    let mut topics: Vec<String> = metric_names(settings).iter().map(|(m, _, _, _)| config_topic(serial, m)).collect();
+    topics.push(config_topic(serial, "sags"));
+    topics.push(config_topic(serial, "swells"));
+    topics
+}
+
+pub fn power_quality_discovery_payloads(
+    serial: &str,
+    unit_name: &str,
+    settings: &PowerQuality,
+    device_info: &DeviceInfo,
+) -> Vec<(String, HAConfigPayload)> {
+    let mut payloads: Vec<(String, HAConfigPayload)> = metric_names(settings)
+        .into_iter()
+        .map(|(metric, path, uom, quantity)| {
+            let name = format!("{unit_name}-{metric}");
+            let config_payload = HAConfigPayload {
+                name: name.clone(),
+                unique_id: name,
+                device: device_info.clone(),
+                state_topic: power_quality_state_topic(serial),
+                // a spread isn't a voltage or frequency reading in its own right
+                device_class: if metric.ends_with("_stddev") { None } else { Some(quantity.to_string()) },
+                state_class: Some("measurement".to_string()),
+                native_uom: uom.map(|u| u.to_string()),
+                entity_category: Some(EntityCategory::Diagnostic),
+                suggested_display_precision: Some(2),
+                value_template: Some(format!("{{{{ {path} }}}}")),
+                ..Default::default()
+            };
+            (config_topic(serial, &metric), config_payload)
+        })
+        .collect();
+    for counter in ["sags", "swells"] {
+        let name = format!("{unit_name}-{counter}");
+        let config_payload = HAConfigPayload {
+            name: name.clone(),
+            unique_id: name,
+            device: device_info.clone(),
+            state_topic: power_quality_state_topic(serial),
+            state_class: Some("total_increasing".to_string()),
+            entity_category: Some(EntityCategory::Diagnostic),
+            value_template: Some(format!("{{{{ value_json.{counter} }}}}")),
+            ..Default::default()
+        };
+        payloads.push((config_topic(serial, counter), config_payload));
+    }
+    payloads
+}
@@ -0,0 +1,96 @@
+use crate::config::Prometheus;
+use crate::consts::SINK_QUEUE_SIZE;
+use crate::readings::MeterSample;
+use crate::sinks::{Sink, SinkMessage};
+use futures::future::BoxFuture;
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::{mpsc, RwLock};
+
+lazy_static! {
+    // latest sample of every meter, keyed by PZEMDevice::key(); None unless the sink is enabled
+    static ref SCRAPE_SAMPLES: RwLock<Option<BTreeMap<String, Arc<MeterSample>>>> = RwLock::new(None);
+}
+
+// (name, type, help, value)
+type MetricDef = (&'static str, &'static str, &'static str, fn(&MeterSample) -> f64);
+
+const METRICS: [MetricDef; 8] = [
+    ("pzem016_volts", "gauge", "Line voltage", |s| s.reading.volts as f64),
+    ("pzem016_amps", "gauge", "Current", |s| s.reading.amps as f64),
+    ("pzem016_watts", "gauge", "Active power", |s| s.reading.watts as f64),
+    ("pzem016_frequency_hertz", "gauge", "Line frequency", |s| s.reading.frequency as f64),
+    ("pzem016_power_factor", "gauge", "Power factor", |s| s.reading.power_factor as f64),
+    ("pzem016_register_watt_hours", "gauge", "Raw energy register of the meter", |s| s.reading.watt_hours as f64),
+    ("pzem016_energy_watt_hours_total", "counter", "Energy used, continuous across register wraps and resets", |s| s.energy_wh),
+    ("pzem016_last_reading_timestamp_seconds", "gauge", "Time of the latest reading", |s| s.at.timestamp_millis() as f64 / 1000.0),
+];
+
+fn escape_label(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// text exposition format, or None when the prometheus sink isn't enabled
+pub async fn render_metrics() -> Option<String> {
+    let samples = SCRAPE_SAMPLES.read().await;
+    let samples = samples.as_ref()?;
+    let mut out = String::new();
+    for (name, kind, help, value) in METRICS.iter() {
+        out.push_str(&format!("# HELP {name} {help}\n# TYPE {name} {kind}\n"));
+        for s in samples.values() {
+            out.push_str(&format!(
+                "{name}{{meter=\"{}\",breaker=\"{}\",port=\"{}\",address=\"{}\"}} {}\n",
+                escape_label(&s.device.key()),
+                escape_label(&s.device.breaker),
+                escape_label(&s.device.port),
+                s.device.addr,
+                value(s)
+            ));
+        }
+    }
+    Some(out)
+}
+
+pub struct PrometheusSink {
+    settings: Prometheus,
+}
+
+impl PrometheusSink {
+    pub fn new(settings: &Prometheus) -> Self {
+        PrometheusSink { settings: settings.clone() }
+    }
+}
+
+impl Sink for PrometheusSink {
+    fn name(&self) -> &'static str {
+        "prometheus"
+    }
+
+    fn queue_size(&self) -> usize {
+        self.settings.queue_size.unwrap_or(SINK_QUEUE_SIZE)
+    }
+
+    fn run(self: Box<Self>, mut rx: mpsc::Receiver<SinkMessage>) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            *SCRAPE_SAMPLES.write().await = Some(BTreeMap::new());
+            while let Some(msg) = rx.recv().await {
+                match msg {
+                    SinkMessage::Sample(s) => {
+                        if let Some(samples) = SCRAPE_SAMPLES.write().await.as_mut() {
+                            samples.insert(s.device.key(), s);
+                        }
+                    }
+                    SinkMessage::Event(_) => {}
+                    // forget meters that were removed from the config
+                    SinkMessage::ConfigReloaded => {
+                        let keys: Vec<String> = crate::SETTINGS.read().await.devices().iter().map(|d| d.key()).collect();
+                        if let Some(samples) = SCRAPE_SAMPLES.write().await.as_mut() {
+                            samples.retain(|k, _| keys.contains(k));
+                        }
+                    }
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,127 @@
+use crate::analysis::AnalysisResults;
+use crate::config::PZEMDevice;
+use crate::tariffs::CostPayload;
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::Instant;
+use tokio::sync::RwLock;
+
+// below this current the meter is effectively idle and ratios involving amps are noise
+const MIN_LOAD_AMPS: f32 = 0.01;
+
+// names accepted by MeterReading::field, matching the JSON state document
+pub const FIELDS: [&str; 10] = [
+    "volts", "amps", "watts", "watt_hours", "frequency", "power_factor",
+    "apparent_power", "reactive_power", "phase_angle", "impedance",
+];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct MeterReading {
+    pub volts: f32,
+    pub amps: f32,
+    pub watts: f32,
+    pub watt_hours: f32,
+    pub frequency: f32,
+    pub power_factor: f32,
+}
+
+impl MeterReading {
+    fn loaded(&self) -> bool {
+        self.amps >= MIN_LOAD_AMPS
+    }
+
+    // VA
+    pub fn apparent_power(&self) -> f32 {
+        self.volts * self.amps
+    }
+
+    // var; the PZEM-016 doesn't report whether the load leads or lags, so this is unsigned
+    pub fn reactive_power(&self) -> f32 {
+        let s = self.apparent_power();
+        (s * s - self.watts * self.watts).max(0.0).sqrt()
+    }
+
+    // degrees
+    pub fn phase_angle(&self) -> Option<f32> {
+        if !self.loaded() {
+            return None;
+        }
+        Some(self.power_factor.clamp(0.0, 1.0).acos().to_degrees())
+    }
+
+    // ohms
+    pub fn impedance(&self) -> Option<f32> {
+        if !self.loaded() {
+            return None;
+        }
+        Some(self.volts / self.amps)
+    }
+
+    // current as a share of the breaker's trip rating
+    pub fn load_percent(&self, device: &PZEMDevice) -> Option<f32> {
+        let rating = device.rating_amps? as f32;
+        Some(self.amps / rating * 100.0)
+    }
+
+    // watts still available before the breaker rating is reached; negative when over it
+    pub fn headroom_watts(&self, device: &PZEMDevice) -> Option<f32> {
+        let rating = device.rating_amps? as f32;
+        let volts = device.circuit_volts.map_or(self.volts, |v| v as f32);
+        Some((rating - self.amps) * volts)
+    }
+
+    pub fn field(&self, name: &str) -> Option<f32> {
+        match name {
+            "volts" => Some(self.volts),
+            "amps" => Some(self.amps),
+            "watts" => Some(self.watts),
+            "watt_hours" => Some(self.watt_hours),
+            "frequency" => Some(self.frequency),
+            "power_factor" => Some(self.power_factor),
+            "apparent_power" => Some(self.apparent_power()),
+            "reactive_power" => Some(self.reactive_power()),
+            "phase_angle" => self.phase_angle(),
+            "impedance" => self.impedance(),
+            _ => None,
+        }
+    }
+}
+
+// one successful poll of one meter, as handed to every output sink
+#[derive(Debug, Clone)]
+pub struct MeterSample {
+    pub device: PZEMDevice,
+    pub at: DateTime<Utc>,
+    pub reading: MeterReading,
+    // continuous energy counter, unaffected by register wraps and resets
+    pub energy_wh: f64,
+    // the same counter from its last requested reset, as published over mqtt
+    pub energy_total_wh: f64,
+    // only when tariffs are configured
+    pub cost: Option<CostPayload>,
+    pub analysis: AnalysisResults,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CachedReading {
+    pub reading: MeterReading,
+    // continuous energy counter, unaffected by register wraps and resets
+    pub energy_wh: f64,
+    // only when tariffs are configured, including any fixed charge billed to this meter
+    pub cost_today: Option<f64>,
+    pub cost_month: Option<f64>,
+    pub at: Instant,
+}
+
+impl CachedReading {
+    pub fn age_secs(&self) -> u64 {
+        self.at.elapsed().as_secs()
+    }
+}
+
+lazy_static! {
+    // latest successful reading of every meter, keyed by PZEMDevice::key()
+    pub static ref LATEST_READINGS: RwLock<HashMap<String, CachedReading>> = RwLock::new(HashMap::new());
+}
@@ -0,0 +1,76 @@
+use crate::config::{config_file_path, AppConfig};
+use crate::consts::CONFIG_RELOAD_DEBOUNCE_MILLIS;
+use crate::errors::ConfigError;
+use crate::ipc::IPCMessage;
+use crate::SETTINGS;
+use notify::{Event, RecursiveMode, Watcher};
+use std::path::Path;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+
+pub async fn config_watch_loop(tx: mpsc::Sender<IPCMessage>) -> Result<(), ConfigError> {
+    let path = config_file_path();
+    // watch the directory rather than the file: kubernetes secret mounts and most editors
+    // replace the file (or a symlink to it) instead of writing in place.
+    let dir = match Path::new(&path).parent() {
+        Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+        _ => Path::new(".").to_path_buf(),
+    };
+    let (fs_tx, mut fs_rx) = mpsc::channel::<()>(1);
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| {
+        if res.is_ok() {
+            let _ = fs_tx.try_send(());
+        }
+    })
+    .map_err(|e| ConfigError::Read(format!("couldn't create file watcher: {e}")))?;
+    watcher
+        .watch(&dir, RecursiveMode::NonRecursive)
+        .map_err(|e| ConfigError::Read(format!("couldn't watch {}: {e}", dir.display())))?;
+    let mut hangup = signal(SignalKind::hangup())
+        .map_err(|e| ConfigError::Read(format!("couldn't install SIGHUP handler: {e}")))?;
+
+    loop {
+        select! {
+            Some(_) = fs_rx.recv() => {
+                // a single save usually produces a burst of events, wait for it to settle
+                sleep(Duration::from_millis(CONFIG_RELOAD_DEBOUNCE_MILLIS)).await;
+                while fs_rx.try_recv().is_ok() {}
+                debug!("config directory changed, checking {path}");
+            }
+            Some(_) = hangup.recv() => {
+                info!("Received SIGHUP, reloading {path}");
+            }
+        }
+        reload(&path, &tx).await;
+    }
+}
+
+async fn reload(path: &str, tx: &mpsc::Sender<IPCMessage>) {
+    let new_config = match AppConfig::load(path) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Config reload failed, keeping current config: {e}");
+            return;
+        }
+    };
+    {
+        let mut settings = SETTINGS.write().await;
+        if *settings == new_config {
+            debug!("config unchanged, nothing to reload");
+            return;
+        }
+        if settings.mqtt_changed(&new_config) {
+            warn!("mqtt connection settings changed; these only take effect after a restart");
+        }
+        if settings.sinks_changed(&new_config) {
+            warn!("sink settings changed; these only take effect after a restart");
+        }
+        *settings = new_config;
+    }
+    info!("Config reloaded from {path}");
+    if let Err(e) = tx.send(IPCMessage::ConfigReloaded).await {
+        error!("Couldn't notify main loop of config reload: {e}");
+    }
+}
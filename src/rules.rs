@@ -0,0 +1,160 @@
+use crate::config::{AlertRule, AppConfig, PZEMDevice};
+use crate::events::BridgeEvent;
+use crate::groups::slug;
+use crate::payload::{DeviceInfo, EntityCategory, HAConfigPayload};
+use crate::readings::MeterReading;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RuleState {
+    Clear,
+    // limit crossed at this instant, waiting out for_secs
+    Pending(Instant),
+    Active,
+}
+
+struct TrackedRule {
+    rule: AlertRule,
+    // resolved against this meter's breaker rating
+    above: Option<f32>,
+    state: RuleState,
+}
+
+impl TrackedRule {
+    fn condition(&self) -> String {
+        let r = &self.rule;
+        match (self.above, r.below) {
+            (Some(a), Some(b)) => format!("{} outside {b}..{a}", r.metric),
+            (Some(a), None) => format!("{} > {a}", r.metric),
+            (None, Some(b)) => format!("{} < {b}", r.metric),
+            (None, None) => r.metric.clone(),
+        }
+    }
+
+    fn violated(&self, v: f32) -> bool {
+        self.above.map_or(false, |a| v > a) || self.rule.below.map_or(false, |b| v < b)
+    }
+
+    fn recovered(&self, v: f32) -> bool {
+        let h = self.rule.hysteresis.unwrap_or(0.0);
+        self.above.map_or(true, |a| v <= a - h) && self.rule.below.map_or(true, |b| v >= b + h)
+    }
+}
+
+// the alert rules for a single meter
+pub struct RuleEngine {
+    device: PZEMDevice,
+    rules: Vec<TrackedRule>,
+}
+
+impl RuleEngine {
+    pub fn new(device: &PZEMDevice, config: &AppConfig) -> Self {
+        RuleEngine {
+            device: device.clone(),
+            rules: config
+                .rules()
+                .iter()
+                .filter(|r| r.applies_to(device))
+                .map(|r| TrackedRule { rule: r.clone(), above: r.above_for(device), state: RuleState::Clear })
+                .collect(),
+        }
+    }
+
+    // returns the events for every rule that fired or cleared on this reading
+    pub fn evaluate(&mut self, reading: &MeterReading, now: Instant) -> Vec<BridgeEvent> {
+        let mut events: Vec<BridgeEvent> = vec![];
+        for tracked in self.rules.iter_mut() {
+            let value = match reading.field(&tracked.rule.metric) {
+                Some(v) => v,
+                None => continue,
+            };
+            let min_duration = tracked.rule.for_secs.unwrap_or(0);
+            let next = match tracked.state {
+                RuleState::Clear if tracked.violated(value) => RuleState::Pending(now),
+                RuleState::Pending(_) if !tracked.violated(value) => RuleState::Clear,
+                RuleState::Active if tracked.recovered(value) => RuleState::Clear,
+                s => s,
+            };
+            let next = match next {
+                RuleState::Pending(since) if now.duration_since(since).as_secs() >= min_duration => RuleState::Active,
+                s => s,
+            };
+            if next == RuleState::Active && tracked.state != RuleState::Active {
+                events.push(BridgeEvent::RuleTriggered {
+                    rule: tracked.rule.name.clone(),
+                    meter: self.device.key(),
+                    breaker: self.device.breaker.clone(),
+                    metric: tracked.rule.metric.clone(),
+                    value,
+                    condition: tracked.condition(),
+                });
+            } else if tracked.state == RuleState::Active && next == RuleState::Clear {
+                events.push(BridgeEvent::RuleCleared {
+                    rule: tracked.rule.name.clone(),
+                    meter: self.device.key(),
+                    breaker: self.device.breaker.clone(),
+                    metric: tracked.rule.metric.clone(),
+                    value,
+                    condition: tracked.condition(),
+                });
+            }
+            tracked.state = next;
+        }
+        events
+    }
+
+    // (rule name, active) for every rule on this meter
+    pub fn states(&self) -> Vec<(&str, bool)> {
+        self.rules
+            .iter()
+            .map(|t| (t.rule.name.as_str(), t.state == RuleState::Active))
+            .collect()
+    }
+}
+
+pub fn rule_state_topic(serial: &str, rule: &str) -> String {
+    format!("pzem016mqtt/pzem016-{serial}/rule_{}", slug(rule))
+}
+
+fn rule_config_topic(serial: &str, rule: &str) -> String {
+    format!("homeassistant/binary_sensor/pzem016-{serial}/rule_{}/config", slug(rule))
+}
+
+pub fn rule_discovery_topics(serial: &str, device: &PZEMDevice, config: &AppConfig) -> Vec<String> {
+    config
+        .rules()
+        .iter()
+        .filter(|r| r.applies_to(device))
+        .map(|r| rule_config_topic(serial, &r.name))
+        .collect()
+}
+
+pub fn rule_discovery_payloads(
+    serial: &str,
+    unit_name: &str,
+    device: &PZEMDevice,
+    config: &AppConfig,
+    device_info: &DeviceInfo,
+) -> Vec<(String, HAConfigPayload)> {
+    config
+        .rules()
+        .iter()
+        .filter(|r| r.applies_to(device))
+        .map(|r| {
+            let name = format!("{unit_name}-rule_{}", slug(&r.name));
+            let config_payload = HAConfigPayload {
+                name: name.clone(),
+                unique_id: name,
+                device: device_info.clone(),
+                state_topic: rule_state_topic(serial, &r.name),
+                device_class: Some("problem".to_string()),
+                entity_category: Some(EntityCategory::Diagnostic),
+                value_template: Some("{{ value_json.value }}".to_string()),
+                payload_on: Some("ON".to_string()),
+                payload_off: Some("OFF".to_string()),
+                ..Default::default()
+            };
+            (rule_config_topic(serial, &r.name), config_payload)
+        })
+        .collect()
+}
@@ -0,0 +1,173 @@
+use crate::config::{AppConfig, MqttFormat};
+use crate::consts::SINK_STOP_TIMEOUT_SECS;
+use crate::events::BridgeEvent;
+use crate::file_sink::FileSink;
+use crate::homie::HomieSink;
+use crate::graphite::GraphiteSink;
+use crate::history::HistorySink;
+use crate::influx::InfluxSink;
+use crate::ipc::IPCMessage;
+use crate::mqtt_formats::MqttFormatSink;
+use crate::mqtt_sink::MqttSink;
+use crate::prometheus::PrometheusSink;
+use crate::readings::MeterSample;
+use crate::sparkplug::SparkplugSink;
+use crate::webhook::WebhookSink;
+use futures::future::BoxFuture;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+#[derive(Debug, Clone)]
+pub enum SinkMessage {
+    Sample(Arc<MeterSample>),
+    // raised outside of any sink, e.g. a meter that stopped answering
+    Event(Arc<BridgeEvent>),
+    // SETTINGS has changed; sinks that cache anything derived from it should rebuild
+    ConfigReloaded,
+}
+
+// a destination for meter readings. Each sink runs as its own task behind its own queue, so
+// a slow sink only ever loses its own readings and never holds up polling.
+pub trait Sink: Send {
+    fn name(&self) -> &'static str;
+    // readings that may queue up for this sink before new ones are dropped
+    fn queue_size(&self) -> usize;
+    // consumes messages until the channel closes
+    fn run(self: Box<Self>, rx: mpsc::Receiver<SinkMessage>) -> BoxFuture<'static, ()>;
+}
+
+struct SinkHandle {
+    name: &'static str,
+    tx: mpsc::Sender<SinkMessage>,
+    task: JoinHandle<()>,
+    // readings dropped since the sink last kept up
+    dropped: u64,
+    // a ConfigReloaded that hasn't fit in the queue yet; it goes ahead of anything else
+    reload_pending: bool,
+}
+
+pub struct SinkSet {
+    sinks: Vec<SinkHandle>,
+}
+
+impl SinkSet {
+    pub fn start(config: &AppConfig, tx: &mpsc::Sender<IPCMessage>) -> Self {
+        let mut enabled: Vec<Box<dyn Sink>> = vec![];
+        if config.mqtt_sink_enabled() {
+            enabled.push(Box::new(MqttSink::new(config, tx.clone())));
+        }
+        for output in config.mqtt_outputs.iter().flatten() {
+            match output.format {
+                MqttFormat::Homie => enabled.push(Box::new(HomieSink::new(output, config))),
+                MqttFormat::Sparkplug => enabled.push(Box::new(SparkplugSink::new(output, config))),
+                _ => enabled.push(Box::new(MqttFormatSink::new(output, config, tx.clone()))),
+            }
+        }
+        if let Some(history) = &config.history {
+            enabled.push(Box::new(HistorySink::new(history, config)));
+        }
+        if let Some(influx) = &config.influx {
+            enabled.push(Box::new(InfluxSink::new(influx)));
+        }
+        if let Some(prometheus) = &config.prometheus {
+            enabled.push(Box::new(PrometheusSink::new(prometheus)));
+        }
+        if let Some(file) = &config.file_sink {
+            enabled.push(Box::new(FileSink::new(file, config.local_timezone())));
+        }
+        if let Some(graphite) = &config.graphite {
+            enabled.push(Box::new(GraphiteSink::new(graphite)));
+        }
+        if let Some(webhook) = &config.webhook {
+            enabled.push(Box::new(WebhookSink::new(webhook, config)));
+        }
+        let sinks = enabled
+            .into_iter()
+            .map(|sink| {
+                let name = sink.name();
+                let (sink_tx, sink_rx) = mpsc::channel::<SinkMessage>(sink.queue_size());
+                info!("starting {name} sink");
+                SinkHandle {
+                    name,
+                    tx: sink_tx,
+                    task: tokio::task::spawn(sink.run(sink_rx)),
+                    dropped: 0,
+                    reload_pending: false,
+                }
+            })
+            .collect();
+        SinkSet { sinks }
+    }
+
+    pub fn offer(&mut self, sample: MeterSample) {
+        self.send(SinkMessage::Sample(Arc::new(sample)));
+    }
+
+    // events are few and each one matters, so a sink that is full gets it as soon as it has room
+    // rather than losing it like a reading
+    pub fn event(&mut self, event: BridgeEvent) {
+        let msg = SinkMessage::Event(Arc::new(event));
+        for sink in self.sinks.iter() {
+            if let Err(TrySendError::Full(msg)) = sink.tx.try_send(msg.clone()) {
+                let tx = sink.tx.clone();
+                tokio::task::spawn(async move {
+                    let _ = tx.send(msg).await;
+                });
+            }
+        }
+    }
+
+    // never waits: a sink whose queue is full loses this message
+    fn send(&mut self, msg: SinkMessage) {
+        for sink in self.sinks.iter_mut() {
+            if sink.reload_pending {
+                sink.reload_pending = sink.tx.try_send(SinkMessage::ConfigReloaded).is_err();
+            }
+            let sent = if sink.reload_pending {
+                Err(TrySendError::Full(msg.clone()))
+            } else {
+                sink.tx.try_send(msg.clone())
+            };
+            match sent {
+                Ok(_) => {
+                    if sink.dropped > 0 {
+                        info!("{} sink caught up after dropping {} readings", sink.name, sink.dropped);
+                        sink.dropped = 0;
+                    }
+                }
+                Err(TrySendError::Full(_)) => {
+                    if sink.dropped == 0 {
+                        warn!("{} sink is falling behind, dropping readings", sink.name);
+                    }
+                    sink.dropped += 1;
+                }
+                Err(TrySendError::Closed(_)) => {
+                    error!("{} sink has stopped", sink.name);
+                }
+            }
+        }
+        self.sinks.retain(|s| !s.tx.is_closed());
+    }
+
+    // never waits either, since a full sink may itself be waiting on the main loop; a sink
+    // that has no room gets it ahead of its next message instead
+    pub fn config_reloaded(&mut self) {
+        for sink in self.sinks.iter_mut() {
+            sink.reload_pending = sink.tx.try_send(SinkMessage::ConfigReloaded).is_err();
+        }
+    }
+
+    // closes every queue and gives the sinks a moment to finish what they have
+    pub async fn stop(self) {
+        for sink in self.sinks {
+            drop(sink.tx);
+            if timeout(Duration::from_secs(SINK_STOP_TIMEOUT_SECS), sink.task).await.is_err() {
+                warn!("{} sink didn't stop in time", sink.name);
+            }
+        }
+    }
+}
@@ -0,0 +1,499 @@
+use crate::config::{AppConfig, MqttOutput, PZEMDevice};
+use crate::consts::SINK_QUEUE_SIZE;
+use crate::events::BridgeEvent;
+use crate::mqtt_connection::MqttConnection;
+use crate::mqtt_formats::{meter_id, metric_values, topic_id};
+use crate::readings::MeterSample;
+use crate::sinks::{Sink, SinkMessage};
+use crate::state::{load_json, save_json};
+use crate::SETTINGS;
+use chrono::Utc;
+use futures::future::BoxFuture;
+use prost::Message;
+use rumqttc::{AsyncClient, Event, Incoming, LastWill, Outgoing, QoS};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+
+// the parts of the Sparkplug B payload schema the bridge uses, with the spec's field numbers
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SparkplugPayload {
+    #[prost(uint64, optional, tag = "1")]
+    pub timestamp: Option<u64>,
+    #[prost(message, repeated, tag = "2")]
+    pub metrics: Vec<Metric>,
+    #[prost(uint64, optional, tag = "3")]
+    pub seq: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Metric {
+    #[prost(string, optional, tag = "1")]
+    pub name: Option<String>,
+    #[prost(uint64, optional, tag = "2")]
+    pub alias: Option<u64>,
+    #[prost(uint64, optional, tag = "3")]
+    pub timestamp: Option<u64>,
+    #[prost(uint32, optional, tag = "4")]
+    pub datatype: Option<u32>,
+    #[prost(bool, optional, tag = "7")]
+    pub is_null: Option<bool>,
+    #[prost(message, optional, tag = "9")]
+    pub properties: Option<PropertySet>,
+    #[prost(oneof = "MetricValue", tags = "11, 13, 14")]
+    pub value: Option<MetricValue>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum MetricValue {
+    #[prost(uint64, tag = "11")]
+    LongValue(u64),
+    #[prost(double, tag = "13")]
+    DoubleValue(f64),
+    #[prost(bool, tag = "14")]
+    BooleanValue(bool),
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PropertySet {
+    #[prost(string, repeated, tag = "1")]
+    pub keys: Vec<String>,
+    #[prost(message, repeated, tag = "2")]
+    pub values: Vec<PropertyValue>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PropertyValue {
+    #[prost(uint32, optional, tag = "1")]
+    pub r#type: Option<u32>,
+    #[prost(string, optional, tag = "8")]
+    pub string_value: Option<String>,
+}
+
+const DATATYPE_INT64: u32 = 4;
+const DATATYPE_DOUBLE: u32 = 10;
+const DATATYPE_BOOLEAN: u32 = 11;
+const DATATYPE_STRING: u32 = 12;
+
+const BD_SEQ_METRIC: &str = "bdSeq";
+const REBIRTH_METRIC: &str = "Node Control/Rebirth";
+// the node's own metrics take the first aliases
+const BD_SEQ_ALIAS: u64 = 0;
+const REBIRTH_ALIAS: u64 = 1;
+
+#[derive(Serialize, Deserialize, Default)]
+struct SparkplugState {
+    bd_seq: Option<u64>,
+}
+
+pub fn sparkplug_topic(output: &MqttOutput, config: &AppConfig, message_type: &str, device: Option<&PZEMDevice>) -> String {
+    let mut topic = format!(
+        "{}/{}/{message_type}/{}",
+        output.base_topic(),
+        output.group_id(),
+        topic_id(&config.client_id())
+    );
+    if let Some(device) = device {
+        topic.push('/');
+        topic.push_str(&meter_id(device));
+    }
+    topic
+}
+
+fn bd_seq_metric(bd_seq: u64) -> Metric {
+    Metric {
+        name: Some(BD_SEQ_METRIC.to_string()),
+        alias: Some(BD_SEQ_ALIAS),
+        datatype: Some(DATATYPE_INT64),
+        value: Some(MetricValue::LongValue(bd_seq)),
+        ..Default::default()
+    }
+}
+
+fn now_millis() -> u64 {
+    Utc::now().timestamp_millis() as u64
+}
+
+// each session takes the next bdSeq, kept across restarts, so a host can tell the death of
+// this session from an earlier one's
+fn next_bd_seq(path: &Path) -> u64 {
+    let mut state = load_json::<SparkplugState>(path).unwrap_or_default();
+    let bd_seq = state.bd_seq.map_or(0, |s| (s + 1) % 256);
+    state.bd_seq = Some(bd_seq);
+    save_json(path, &state);
+    bd_seq
+}
+
+fn death_payload(bd_seq: u64) -> Vec<u8> {
+    SparkplugPayload {
+        timestamp: Some(now_millis()),
+        metrics: vec![bd_seq_metric(bd_seq)],
+        seq: None,
+    }
+    .encode_to_vec()
+}
+
+// the NDEATH the broker publishes for us if the session ends without one
+fn death_will(topic: &str, bd_seq: u64) -> LastWill {
+    LastWill::new(topic, death_payload(bd_seq), QoS::AtLeastOnce, false)
+}
+
+// what the edge node's connection has to tell the sink
+enum Notice {
+    // a session has started with this bdSeq
+    Connected(u64),
+    Disconnected,
+    // an NCMD payload
+    Command(Vec<u8>),
+}
+
+// drives the edge node's connection: subscribes to its commands and registers a will with a
+// new bdSeq before every reconnect
+async fn sparkplug_event_loop(
+    mqtt: MqttConnection,
+    state_path: PathBuf,
+    mut bd_seq: u64,
+    death_topic: String,
+    command_topic: String,
+    notices: mpsc::UnboundedSender<Notice>,
+) {
+    let client = mqtt.client.clone();
+    let mut conn = mqtt.event_loop;
+    loop {
+        match conn.poll().await {
+            Ok(Event::Incoming(Incoming::ConnAck(_))) => {
+                if let Err(e) = client.subscribe(command_topic.clone(), QoS::AtMostOnce).await {
+                    warn!("couldn't subscribe to {command_topic}: {e}");
+                }
+                let _ = notices.send(Notice::Connected(bd_seq));
+            }
+            Ok(Event::Incoming(Incoming::Publish(p))) if p.topic == command_topic => {
+                let _ = notices.send(Notice::Command(p.payload.to_vec()));
+            }
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => return,
+            Ok(_) => {}
+            Err(e) => {
+                warn!("sparkplug connection failed: {e}");
+                let _ = notices.send(Notice::Disconnected);
+                bd_seq = next_bd_seq(&state_path);
+                conn.mqtt_options.set_last_will(death_will(&death_topic, bd_seq));
+                sleep(Duration::from_secs(5)).await;
+            }
+        }
+    }
+}
+
+struct SparkplugDevice {
+    device: PZEMDevice,
+    latest: Arc<MeterSample>,
+    born: bool,
+}
+
+// the edge node, on a connection of its own so its NDEATH can be that connection's will
+struct EdgeNode {
+    client: AsyncClient,
+    task: JoinHandle<()>,
+    notices: mpsc::UnboundedReceiver<Notice>,
+}
+
+pub struct SparkplugSink {
+    output: MqttOutput,
+    config: AppConfig,
+    client: Option<AsyncClient>,
+    // the bdSeq of the current session
+    bd_seq: u64,
+    connected: bool,
+    node_born: bool,
+    seq: u64,
+    devices: HashMap<String, SparkplugDevice>,
+    // given out at birth and used in place of names in DDATA, by meter key and metric
+    aliases: HashMap<(String, &'static str), u64>,
+    next_alias: u64,
+}
+
+impl SparkplugSink {
+    pub fn new(output: &MqttOutput, config: &AppConfig) -> Self {
+        SparkplugSink {
+            output: output.clone(),
+            config: config.clone(),
+            client: None,
+            bd_seq: 0,
+            connected: false,
+            node_born: false,
+            seq: 0,
+            devices: HashMap::new(),
+            aliases: HashMap::new(),
+            next_alias: REBIRTH_ALIAS + 1,
+        }
+    }
+
+    fn topic(&self, message_type: &str, device: Option<&PZEMDevice>) -> String {
+        sparkplug_topic(&self.output, &self.config, message_type, device)
+    }
+
+    async fn connect(&mut self) -> Option<EdgeNode> {
+        let mut mqtt = match MqttConnection::new(
+            format!("{}-sparkplug", self.config.client_id()),
+            self.config.mqtt_server_addr.clone(),
+            self.config.mqtt_server_port.unwrap_or(1883),
+            self.config.mqtt_username.clone(),
+            self.config.mqtt_password.clone(),
+            None,
+        )
+        .await
+        {
+            Ok(m) => m,
+            Err(e) => {
+                error!("couldn't create sparkplug connection: {e}");
+                return None;
+            }
+        };
+        let state_path = Path::new(self.config.state_dir()).join("sparkplug.json");
+        let death_topic = self.topic("NDEATH", None);
+        self.bd_seq = next_bd_seq(&state_path);
+        mqtt.event_loop.mqtt_options.set_last_will(death_will(&death_topic, self.bd_seq));
+        let client = mqtt.client.clone();
+        let (notices_tx, notices) = mpsc::unbounded_channel::<Notice>();
+        let task = tokio::task::spawn(sparkplug_event_loop(
+            mqtt,
+            state_path,
+            self.bd_seq,
+            death_topic,
+            self.topic("NCMD", None),
+            notices_tx,
+        ));
+        self.client = Some(client.clone());
+        Some(EdgeNode { client, task, notices })
+    }
+
+    // sequence numbers wrap at 256 and every message from the node takes the next one
+    fn next_seq(&mut self) -> u64 {
+        let seq = self.seq;
+        self.seq = (self.seq + 1) % 256;
+        seq
+    }
+
+    async fn send(&mut self, topic: String, metrics: Vec<Metric>) {
+        let payload = SparkplugPayload {
+            timestamp: Some(now_millis()),
+            metrics,
+            seq: Some(self.next_seq()),
+        };
+        self.publish(topic, payload.encode_to_vec()).await;
+    }
+
+    // births, deaths and data all go at qos 0, as the spec has it
+    async fn publish(&self, topic: String, payload: Vec<u8>) {
+        let client = match &self.client {
+            Some(c) => c,
+            None => return,
+        };
+        match timeout(Duration::from_secs(3), client.publish(topic.clone(), QoS::AtMostOnce, false, payload)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => warn!("couldn't publish {topic}: {e}"),
+            Err(_) => warn!("timed out publishing {topic}"),
+        }
+    }
+
+    // NBIRTH restarts the sequence and the aliases, so every device has to be born again after it
+    async fn birth_node(&mut self) {
+        self.seq = 0;
+        self.aliases.clear();
+        self.next_alias = REBIRTH_ALIAS + 1;
+        let metrics = vec![
+            bd_seq_metric(self.bd_seq),
+            Metric {
+                name: Some(REBIRTH_METRIC.to_string()),
+                alias: Some(REBIRTH_ALIAS),
+                datatype: Some(DATATYPE_BOOLEAN),
+                value: Some(MetricValue::BooleanValue(false)),
+                ..Default::default()
+            },
+        ];
+        self.send(self.topic("NBIRTH", None), metrics).await;
+        self.node_born = true;
+        for d in self.devices.values_mut() {
+            d.born = false;
+        }
+    }
+
+    fn alias(&mut self, meter: &str, metric: &'static str) -> u64 {
+        let next_alias = &mut self.next_alias;
+        *self.aliases.entry((meter.to_string(), metric)).or_insert_with(|| {
+            let alias = *next_alias;
+            *next_alias += 1;
+            alias
+        })
+    }
+
+    fn metrics(&mut self, sample: &MeterSample, birth: bool) -> Vec<Metric> {
+        let timestamp = sample.at.timestamp_millis() as u64;
+        let meter = sample.device.key();
+        metric_values(sample)
+            .into_iter()
+            .map(|(metric, _, unit, value)| Metric {
+                // births name every metric; data only needs the alias
+                name: if birth { Some(metric.to_string()) } else { None },
+                alias: Some(self.alias(&meter, metric)),
+                timestamp: Some(timestamp),
+                datatype: Some(DATATYPE_DOUBLE),
+                is_null: if value.is_none() { Some(true) } else { None },
+                properties: match (birth, unit) {
+                    (true, Some(unit)) => Some(PropertySet {
+                        keys: vec!["engUnit".to_string()],
+                        values: vec![PropertyValue {
+                            r#type: Some(DATATYPE_STRING),
+                            string_value: Some(unit.to_string()),
+                        }],
+                    }),
+                    _ => None,
+                },
+                value: value.map(MetricValue::DoubleValue),
+            })
+            .collect()
+    }
+
+    async fn handle(&mut self, sample: Arc<MeterSample>) {
+        if self.connected && !self.node_born {
+            self.birth_node().await;
+        }
+        let key = sample.device.key();
+        let born = self.devices.get(&key).is_some_and(|d| d.born && d.device == sample.device);
+        self.devices.insert(key, SparkplugDevice {
+            device: sample.device.clone(),
+            latest: sample.clone(),
+            born: self.connected,
+        });
+        // between sessions readings are only kept for the births that start the next one
+        if !self.connected {
+            return;
+        }
+        let message_type = if born { "DDATA" } else { "DBIRTH" };
+        let metrics = self.metrics(&sample, !born);
+        self.send(self.topic(message_type, Some(&sample.device)), metrics).await;
+    }
+
+    async fn rebirth(&mut self) {
+        info!("sparkplug rebirth");
+        self.birth_node().await;
+        let latest: Vec<Arc<MeterSample>> = self.devices.values().map(|d| d.latest.clone()).collect();
+        for sample in latest {
+            self.handle(sample).await;
+        }
+    }
+
+    async fn death_device(&mut self, meter: &str) {
+        let device = match self.devices.get_mut(meter) {
+            Some(d) if d.born => {
+                d.born = false;
+                d.device.clone()
+            }
+            _ => return,
+        };
+        if self.connected {
+            self.send(self.topic("DDEATH", Some(&device)), vec![]).await;
+        }
+    }
+
+    async fn handle_command(&mut self, payload: &[u8]) {
+        let command = match SparkplugPayload::decode(payload) {
+            Ok(c) => c,
+            Err(e) => {
+                warn!("couldn't decode sparkplug command: {e}");
+                return;
+            }
+        };
+        let rebirth = command.metrics.iter().any(|m| {
+            (m.name.as_deref() == Some(REBIRTH_METRIC) || m.alias == Some(REBIRTH_ALIAS))
+                && m.value == Some(MetricValue::BooleanValue(true))
+        });
+        if rebirth && self.connected {
+            self.rebirth().await;
+        }
+    }
+
+    async fn handle_notice(&mut self, notice: Notice) {
+        match notice {
+            // the broker published the last session's NDEATH, so everything is born again
+            Notice::Connected(bd_seq) => {
+                self.bd_seq = bd_seq;
+                self.connected = true;
+                self.rebirth().await;
+            }
+            Notice::Disconnected => {
+                self.connected = false;
+                self.node_born = false;
+            }
+            Notice::Command(payload) => self.handle_command(&payload).await,
+        }
+    }
+
+    async fn handle_message(&mut self, msg: SinkMessage) {
+        match msg {
+            SinkMessage::Sample(sample) => self.handle(sample).await,
+            SinkMessage::Event(event) => {
+                if let BridgeEvent::MeterOffline { meter, .. } = event.as_ref() {
+                    self.death_device(meter).await;
+                }
+            }
+            SinkMessage::ConfigReloaded => {
+                self.config = SETTINGS.read().await.clone();
+                let keys: Vec<String> = self.config.devices().iter().map(|d| d.key()).collect();
+                let removed: Vec<String> = self.devices.keys().filter(|k| !keys.contains(k)).cloned().collect();
+                for key in removed {
+                    self.death_device(&key).await;
+                    self.devices.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+impl Sink for SparkplugSink {
+    fn name(&self) -> &'static str {
+        "mqtt_sparkplug"
+    }
+
+    fn queue_size(&self) -> usize {
+        self.output.queue_size.unwrap_or(SINK_QUEUE_SIZE)
+    }
+
+    fn run(mut self: Box<Self>, mut rx: mpsc::Receiver<SinkMessage>) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            let mut node = match self.connect().await {
+                Some(n) => n,
+                None => {
+                    while rx.recv().await.is_some() {}
+                    return;
+                }
+            };
+            loop {
+                select! {
+                    msg = rx.recv() => match msg {
+                        Some(msg) => self.handle_message(msg).await,
+                        None => break,
+                    },
+                    Some(notice) = node.notices.recv() => self.handle_notice(notice).await,
+                }
+            }
+            // a clean shutdown announces its own deaths, since the broker only publishes the will
+            // when a session ends uncleanly
+            let keys: Vec<String> = self.devices.keys().cloned().collect();
+            for key in keys {
+                self.death_device(&key).await;
+            }
+            if self.connected && self.node_born {
+                self.publish(self.topic("NDEATH", None), death_payload(self.bd_seq)).await;
+            }
+            let _ = node.client.disconnect().await;
+            if timeout(Duration::from_secs(1), node.task).await.is_err() {
+                warn!("sparkplug connection didn't close in time");
+            }
+        })
+    }
+}
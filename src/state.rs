@@ -0,0 +1,38 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+
+// small JSON files under state_dir that have to survive restarts
+
+pub fn load_json<T: DeserializeOwned>(path: &Path) -> Option<T> {
+    let s = fs::read_to_string(path).ok()?;
+    match serde_json::from_str::<T>(&s) {
+        Ok(t) => Some(t),
+        Err(e) => {
+            warn!("Ignoring unreadable state file {}: {e}", path.display());
+            None
+        }
+    }
+}
+
+// written to a temp file and renamed so a crash never leaves a truncated file behind
+pub fn save_json<T: Serialize>(path: &Path, value: &T) {
+    if let Some(dir) = path.parent() {
+        if let Err(e) = fs::create_dir_all(dir) {
+            warn!("Couldn't create state directory {}: {e}", dir.display());
+            return;
+        }
+    }
+    let json = match serde_json::to_string_pretty(value) {
+        Ok(j) => j,
+        Err(e) => {
+            error!("Couldn't serialize {}: {e}", path.display());
+            return;
+        }
+    };
+    let tmp = path.with_extension("json.tmp");
+    if let Err(e) = fs::write(&tmp, json).and_then(|_| fs::rename(&tmp, path)) {
+        warn!("Couldn't write state file {}: {e}", path.display());
+    }
+}
@@ -0,0 +1,282 @@
+use crate::config::{AppConfig, Tariffs};
+use crate::energy_periods::PeriodSchedule;
+use crate::groups::slug;
+use crate::payload::{config_topic, DeviceInfo, HAConfigPayload};
+use crate::state::{load_json, save_json};
+use chrono::{DateTime, Datelike, NaiveDate, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+const SAVE_INTERVAL_SECS: u64 = 60;
+
+struct RatePeriod {
+    name: String,
+    days: Option<Vec<Weekday>>,
+    start: NaiveTime,
+    end: NaiveTime,
+    price_per_kwh: f64,
+}
+
+impl RatePeriod {
+    fn matches(&self, day: Weekday, time: NaiveTime) -> bool {
+        if let Some(days) = &self.days {
+            if !days.contains(&day) {
+                return false;
+            }
+        }
+        if self.start < self.end {
+            time >= self.start && time < self.end
+        } else if self.start > self.end {
+            time >= self.start || time < self.end
+        } else {
+            true
+        }
+    }
+}
+
+pub struct TariffSchedule {
+    pub currency: String,
+    tz: Tz,
+    periods: Vec<RatePeriod>,
+    pub days: PeriodSchedule,
+    pub tariffs: Tariffs,
+}
+
+impl TariffSchedule {
+    pub fn new(tariffs: &Tariffs, config: &AppConfig) -> Result<Self, String> {
+        let tz = tariffs.timezone(config)?;
+        let periods = tariffs
+            .periods
+            .iter()
+            .map(|p| {
+                Ok(RatePeriod {
+                    name: p.name.clone(),
+                    days: p.days()?,
+                    start: p.start()?,
+                    end: p.end()?,
+                    price_per_kwh: p.price_per_kwh,
+                })
+            })
+            .collect::<Result<Vec<RatePeriod>, String>>()?;
+        let days = match &config.energy_periods {
+            Some(ep) => PeriodSchedule::new(ep)?,
+            None => PeriodSchedule::daily(tz),
+        };
+        Ok(TariffSchedule {
+            currency: tariffs.currency.clone(),
+            tz,
+            periods,
+            days,
+            tariffs: tariffs.clone(),
+        })
+    }
+
+    // (period name, price per kWh) in force at `t`
+    pub fn price_at(&self, t: DateTime<Utc>) -> Option<(&str, f64)> {
+        let local = t.with_timezone(&self.tz);
+        self.periods
+            .iter()
+            .find(|p| p.matches(local.weekday(), local.time()))
+            .map(|p| (p.name.as_str(), p.price_per_kwh))
+    }
+
+    // the first minute of the week no period covers; energy used then couldn't be priced
+    pub fn uncovered(&self) -> Option<(Weekday, NaiveTime)> {
+        let days = [Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri, Weekday::Sat, Weekday::Sun];
+        let minutes = (0..24 * 60).filter_map(|m| NaiveTime::from_hms_opt(m / 60, m % 60, 0));
+        days.into_iter()
+            .flat_map(|day| minutes.clone().map(move |time| (day, time)))
+            .find(|(day, time)| !self.periods.iter().any(|p| p.matches(*day, *time)))
+    }
+
+    pub fn rate_per_hour(&self, watts: f32, t: DateTime<Utc>) -> f64 {
+        self.price_at(t).map_or(0.0, |(_, price)| watts as f64 / 1000.0 * price)
+    }
+
+    // fixed charges for `name` accumulated so far today and this month
+    pub fn fixed_charges(&self, name: &str, t: DateTime<Utc>) -> (f64, f64) {
+        let fixed = self.tariffs.fixed_charge_for(name);
+        let day = self.days.day_of(t);
+        let days_in_month = (day - self.days.month_of(day)).num_days() + 1;
+        (fixed, fixed * days_in_month as f64)
+    }
+
+    pub fn payload(&self, today: f64, month: f64, watts: f32, t: DateTime<Utc>) -> CostPayload {
+        let day = self.days.day_of(t);
+        let price = self.price_at(t);
+        CostPayload {
+            rate_per_hour: self.rate_per_hour(watts, t),
+            today,
+            month,
+            currency: self.currency.clone(),
+            tariff: price.map(|(name, _)| name.to_string()),
+            price_per_kwh: price.map(|(_, p)| p),
+            today_reset: self.days.start_of(day),
+            month_reset: self.days.start_of(self.days.month_of(day)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CostPayload {
+    pub rate_per_hour: f64,
+    pub today: f64,
+    pub month: f64,
+    pub currency: String,
+    pub tariff: Option<String>,
+    pub price_per_kwh: Option<f64>,
+    pub today_reset: String,
+    pub month_reset: String,
+}
+
+// energy cost of one meter, priced at the tariff in force when the energy was used
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CostCounters {
+    pub day: Option<NaiveDate>,
+    pub last_energy_wh: Option<f64>,
+    // energy cost only, fixed charges are added when publishing
+    pub today: f64,
+    pub month: f64,
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    last_save: Option<Instant>,
+}
+
+impl CostCounters {
+    pub fn load(state_dir: &str, meter_key: &str) -> Self {
+        let path = Path::new(state_dir).join("cost").join(format!("{}.json", slug(meter_key)));
+        let mut counters = load_json::<CostCounters>(&path).unwrap_or_default();
+        counters.path = path;
+        counters
+    }
+
+    pub fn update(&mut self, energy_wh: f64, t: DateTime<Utc>, schedule: &TariffSchedule) {
+        let day = schedule.days.day_of(t);
+        let mut rolled_over = false;
+        if let Some(prev) = self.day {
+            if prev != day {
+                self.today = 0.0;
+                if schedule.days.month_of(prev) != schedule.days.month_of(day) {
+                    self.month = 0.0;
+                }
+                rolled_over = true;
+            }
+        }
+        self.day = Some(day);
+        if let (Some(last), Some((_, price))) = (self.last_energy_wh, schedule.price_at(t)) {
+            let delta = energy_wh - last;
+            if delta > 0.0 {
+                let cost = delta / 1000.0 * price;
+                self.today += cost;
+                self.month += cost;
+            }
+        }
+        self.last_energy_wh = Some(energy_wh);
+        if rolled_over || self.last_save.map_or(true, |t| t.elapsed().as_secs() >= SAVE_INTERVAL_SECS) {
+            self.save();
+        }
+    }
+
+    pub fn save(&mut self) {
+        self.last_save = Some(Instant::now());
+        save_json(&self.path, &self);
+    }
+}
+
+pub fn cost_discovery_topics(serial: &str) -> Vec<String> {
+    ["cost_rate", "cost_today", "cost_month"].iter().map(|m| config_topic(serial, m)).collect()
+}
+
+pub fn cost_discovery_payloads(
+    serial: &str,
+    unit_name: &str,
+    state_topic: &str,
+    currency: &str,
+    device_info: &DeviceInfo,
+) -> Vec<(String, HAConfigPayload)> {
+    let sensor = |metric: &str| -> HAConfigPayload {
+        let name = format!("{unit_name}-{metric}");
+        HAConfigPayload {
+            name: name.clone(),
+            unique_id: name,
+            device: device_info.clone(),
+            state_topic: state_topic.to_string(),
+            suggested_display_precision: Some(2),
+            ..Default::default()
+        }
+    };
+    let mut rate = sensor("cost_rate");
+    // HA only allows the monetary class on totals, so the hourly rate is a plain measurement
+    rate.state_class = Some("measurement".to_string());
+    rate.native_uom = Some(format!("{currency}/h"));
+    rate.value_template = Some("{{ value_json.rate_per_hour }}".to_string());
+
+    let mut today = sensor("cost_today");
+    today.device_class = Some("monetary".to_string());
+    today.state_class = Some("total".to_string());
+    today.native_uom = Some(currency.to_string());
+    today.value_template = Some("{{ value_json.today }}".to_string());
+    today.last_reset_value_template = Some("{{ value_json.today_reset }}".to_string());
+
+    let mut month = sensor("cost_month");
+    month.device_class = Some("monetary".to_string());
+    month.state_class = Some("total".to_string());
+    month.native_uom = Some(currency.to_string());
+    month.value_template = Some("{{ value_json.month }}".to_string());
+    month.last_reset_value_template = Some("{{ value_json.month_reset }}".to_string());
+
+    vec![
+        (config_topic(serial, "cost_rate"), rate),
+        (config_topic(serial, "cost_today"), today),
+        (config_topic(serial, "cost_month"), month),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn period(days: Option<Vec<Weekday>>, start: (u32, u32), end: (u32, u32)) -> RatePeriod {
+        RatePeriod {
+            name: "test".to_string(),
+            days,
+            start: NaiveTime::from_hms_opt(start.0, start.1, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(end.0, end.1, 0).unwrap(),
+            price_per_kwh: 0.1,
+        }
+    }
+
+    fn at(h: u32, m: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(h, m, 0).unwrap()
+    }
+
+    #[tokio::test]
+    async fn period_within_a_day() {
+        let p = period(None, (7, 0), (23, 0));
+        assert!(!p.matches(Weekday::Mon, at(6, 59)));
+        assert!(p.matches(Weekday::Mon, at(7, 0)));
+        assert!(p.matches(Weekday::Mon, at(22, 59)));
+        assert!(!p.matches(Weekday::Mon, at(23, 0)));
+    }
+
+    #[tokio::test]
+    async fn period_across_midnight() {
+        let p = period(None, (23, 0), (7, 0));
+        assert!(p.matches(Weekday::Mon, at(23, 0)));
+        assert!(p.matches(Weekday::Tue, at(0, 0)));
+        assert!(p.matches(Weekday::Tue, at(6, 59)));
+        assert!(!p.matches(Weekday::Tue, at(7, 0)));
+        assert!(!p.matches(Weekday::Tue, at(12, 0)));
+    }
+
+    #[tokio::test]
+    async fn equal_start_and_end_is_the_whole_day() {
+        let p = period(Some(vec![Weekday::Sat, Weekday::Sun]), (0, 0), (0, 0));
+        assert!(p.matches(Weekday::Sat, at(0, 0)));
+        assert!(p.matches(Weekday::Sun, at(23, 59)));
+        assert!(!p.matches(Weekday::Mon, at(12, 0)));
+    }
+}
@@ -0,0 +1,49 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampFormat {
+    #[default]
+    Rfc3339,
+    EpochSeconds,
+    EpochMillis,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Timestamp {
+    Text(String),
+    Epoch(u64),
+}
+
+impl TimestampFormat {
+    pub fn format(&self, t: SystemTime) -> Timestamp {
+        let since_epoch = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+        match self {
+            TimestampFormat::Rfc3339 => {
+                Timestamp::Text(DateTime::<Utc>::from(t).to_rfc3339_opts(SecondsFormat::Millis, true))
+            }
+            TimestampFormat::EpochSeconds => Timestamp::Epoch(since_epoch.as_secs()),
+            TimestampFormat::EpochMillis => Timestamp::Epoch(since_epoch.as_millis() as u64),
+        }
+    }
+
+    pub fn now(&self) -> Timestamp {
+        self.format(SystemTime::now())
+    }
+
+    // HA template turning a timestamp at `path` into something a `timestamp` sensor accepts
+    pub fn ha_template(&self, path: &str) -> String {
+        match self {
+            TimestampFormat::Rfc3339 => format!("{{{{ {path} }}}}"),
+            TimestampFormat::EpochSeconds => {
+                format!("{{{{ {path} | int | timestamp_custom('%Y-%m-%dT%H:%M:%S+00:00', false) }}}}")
+            }
+            TimestampFormat::EpochMillis => {
+                format!("{{{{ ({path} / 1000) | int | timestamp_custom('%Y-%m-%dT%H:%M:%S+00:00', false) }}}}")
+            }
+        }
+    }
+}
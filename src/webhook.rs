@@ -0,0 +1,240 @@
+use crate::config::{AppConfig, Webhook};
+use crate::consts::SINK_QUEUE_SIZE;
+use crate::events::{BridgeEvent, EventPayload};
+use crate::sinks::{Sink, SinkMessage};
+use crate::SETTINGS;
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use serde::Serialize;
+use serde_json::Value;
+use std::time::Duration;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+
+#[derive(Serialize)]
+struct DeadLetter<'a> {
+    failed_at: DateTime<Utc>,
+    url: &'a str,
+    error: String,
+    body: &'a str,
+}
+
+// replaces every {{ name }} with that field of `fields`; strings are json-escaped but left
+// unquoted so they can sit inside a quoted template value
+fn render_template(template: &str, fields: &Value, json: &str) -> String {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => break,
+        };
+        out.push_str(&rest[..start]);
+        let name = rest[start + 2..end].trim();
+        match (name, fields.get(name)) {
+            ("json", _) => out.push_str(json),
+            (_, Some(Value::String(s))) => {
+                let quoted = Value::String(s.clone()).to_string();
+                out.push_str(&quoted[1..quoted.len() - 1]);
+            }
+            (_, Some(v)) => out.push_str(&v.to_string()),
+            (_, None) => {}
+        }
+        rest = &rest[end + 2..];
+    }
+    out.push_str(rest);
+    out
+}
+
+pub struct WebhookSink {
+    settings: Webhook,
+    config: AppConfig,
+}
+
+impl WebhookSink {
+    pub fn new(settings: &Webhook, config: &AppConfig) -> Self {
+        WebhookSink {
+            settings: settings.clone(),
+            config: config.clone(),
+        }
+    }
+
+    // the request body for `event`, or None if the webhook doesn't send that kind
+    fn render(&self, event: BridgeEvent) -> Option<(String, String)> {
+        let payload = EventPayload {
+            event,
+            timestamp: self.config.timestamp_format().now(),
+        };
+        let fields = match serde_json::to_value(&payload) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("couldn't serialize webhook event: {e}");
+                return None;
+            }
+        };
+        let name = fields.get("event").and_then(|e| e.as_str()).unwrap_or_default().to_string();
+        if !self.settings.sends(&name) {
+            return None;
+        }
+        let json = fields.to_string();
+        let body = match &self.settings.body_template {
+            Some(template) => render_template(template, &fields, &json),
+            None => json.clone(),
+        };
+        Some((name, body))
+    }
+}
+
+// posts rendered events one after another, retrying each before moving on, so a down endpoint
+// holds up this task rather than the sink's queue
+struct WebhookDelivery {
+    settings: Webhook,
+    http: reqwest::Client,
+    dead_letter_path: String,
+}
+
+impl WebhookDelivery {
+    fn new(settings: &Webhook, config: &AppConfig) -> Self {
+        let http = match reqwest::Client::builder().timeout(Duration::from_secs(settings.timeout_secs())).build() {
+            Ok(c) => c,
+            Err(e) => {
+                error!("couldn't apply webhook timeout: {e}");
+                reqwest::Client::new()
+            }
+        };
+        WebhookDelivery {
+            settings: settings.clone(),
+            http,
+            dead_letter_path: settings.dead_letter_path(config),
+        }
+    }
+
+    async fn run(self, mut rx: mpsc::UnboundedReceiver<(String, String)>) {
+        while let Some((name, body)) = rx.recv().await {
+            if let Err(e) = self.post(&body).await {
+                error!("giving up on {name} webhook: {e}");
+                self.dead_letter(&body, e).await;
+            }
+        }
+    }
+
+    async fn post(&self, body: &str) -> Result<(), String> {
+        let mut backoff = Duration::from_secs(1);
+        let mut last_error = String::new();
+        for attempt in 0..=self.settings.max_retries() {
+            let mut request = self
+                .http
+                .post(&self.settings.url)
+                .header("Content-Type", "application/json")
+                .body(body.to_string());
+            for (name, value) in self.settings.headers.iter().flatten() {
+                request = request.header(name, value);
+            }
+            match request.send().await {
+                Ok(resp) if resp.status().is_success() => return Ok(()),
+                // the endpoint refused the request, sending it again won't change that
+                Ok(resp) if resp.status().is_client_error() && resp.status().as_u16() != 429 => {
+                    return Err(format!("rejected with {}", resp.status()));
+                }
+                Ok(resp) => last_error = resp.status().to_string(),
+                Err(e) => last_error = e.to_string(),
+            }
+            warn!("webhook attempt {} failed: {last_error}", attempt + 1);
+            if attempt < self.settings.max_retries() {
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(Duration::from_secs(60));
+            }
+        }
+        Err(format!("{last_error} after {} retries", self.settings.max_retries()))
+    }
+
+    async fn dead_letter(&self, body: &str, error: String) {
+        let letter = DeadLetter {
+            failed_at: Utc::now(),
+            url: &self.settings.url,
+            error,
+            body,
+        };
+        let line = match serde_json::to_string(&letter) {
+            Ok(l) => l + "\n",
+            Err(e) => {
+                error!("couldn't serialize webhook dead letter: {e}");
+                return;
+            }
+        };
+        let result = match OpenOptions::new().create(true).append(true).open(&self.dead_letter_path).await {
+            Ok(mut f) => f.write_all(line.as_bytes()).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            error!("couldn't write webhook dead letter to {}: {e}", self.dead_letter_path);
+        }
+    }
+}
+
+impl Sink for WebhookSink {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn queue_size(&self) -> usize {
+        self.settings.queue_size.unwrap_or(SINK_QUEUE_SIZE)
+    }
+
+    fn run(mut self: Box<Self>, mut rx: mpsc::Receiver<SinkMessage>) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            let (delivery_tx, delivery_rx) = mpsc::unbounded_channel::<(String, String)>();
+            let delivery = tokio::task::spawn(WebhookDelivery::new(&self.settings, &self.config).run(delivery_rx));
+            while let Some(msg) = rx.recv().await {
+                match msg {
+                    SinkMessage::Event(event) => {
+                        if let Some(rendered) = self.render((*event).clone()) {
+                            let _ = delivery_tx.send(rendered);
+                        }
+                    }
+                    SinkMessage::Sample(_) => {}
+                    SinkMessage::ConfigReloaded => {
+                        self.config = SETTINGS.read().await.clone();
+                    }
+                }
+            }
+            drop(delivery_tx);
+            let _ = delivery.await;
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn fields_are_substituted() {
+        let fields = json!({"meter": "pzem-1", "failed_reads": 3});
+        let out = render_template("{{meter}} missed {{ failed_reads }} reads", &fields, "{}");
+        assert_eq!(out, "pzem-1 missed 3 reads");
+    }
+
+    #[tokio::test]
+    async fn strings_are_escaped_but_unquoted() {
+        let fields = json!({"breaker": "say \"hi\""});
+        let out = render_template(r#"{"text": "{{ breaker }}"}"#, &fields, "{}");
+        assert_eq!(out, r#"{"text": "say \"hi\""}"#);
+    }
+
+    #[tokio::test]
+    async fn json_is_the_whole_payload_and_unknown_names_are_empty() {
+        let fields = json!({"meter": "pzem-1"});
+        let out = render_template("{{ json }}|{{ nope }}|", &fields, r#"{"meter":"pzem-1"}"#);
+        assert_eq!(out, r#"{"meter":"pzem-1"}||"#);
+    }
+
+    #[tokio::test]
+    async fn unclosed_braces_are_left_alone() {
+        let out = render_template("a {{ meter", &json!({"meter": "x"}), "{}");
+        assert_eq!(out, "a {{ meter");
+    }
+}